@@ -72,11 +72,22 @@ async fn run_http_server(mut app: tide::Server<Arc<UcpStreamMetrics>>, addr: Str
             let srtt = metrics.get_srtt();
             let rttvar = metrics.get_rttvar();
             let rx_seq = metrics.get_rx_seq();
+            let send_delay = metrics.get_send_delay();
+            let recv_delay = metrics.get_recv_delay();
 
             Ok(format!(
                 "send_queue: {}\nrecv_queue: {}\nsend_buffer: {}\nrto: {}\nsrtt: {}\n\
-                 rttvar: {}\nuna: {}\nrx_seq: {}",
-                send_queue, recv_queue, send_buffer, rto, srtt, rttvar, una, rx_seq
+                 rttvar: {}\nuna: {}\nrx_seq: {}\nsend_delay: {}\nrecv_delay: {}",
+                send_queue,
+                recv_queue,
+                send_buffer,
+                rto,
+                srtt,
+                rttvar,
+                una,
+                rx_seq,
+                send_delay,
+                recv_delay
             ))
         });
 
@@ -54,12 +54,25 @@ async fn run_http_server(mut app: tide::Server<Arc<UcpListenerMetrics>>, addr: S
                 let srtt = m.get_srtt();
                 let rttvar = m.get_rttvar();
                 let rx_seq = m.get_rx_seq();
+                let send_delay = m.get_send_delay();
+                let recv_delay = m.get_recv_delay();
 
                 result = result
                     + &format!(
                         "remote_addr: {}\nsend_queue: {}\nrecv_queue: {}\n\
-                         send_buffer: {}\nrto: {}\nsrtt: {}\nrttvar: {}\nuna: {}\nrx_seq: {}\n\n",
-                        a, send_queue, recv_queue, send_buffer, rto, srtt, rttvar, una, rx_seq
+                         send_buffer: {}\nrto: {}\nsrtt: {}\nrttvar: {}\nuna: {}\nrx_seq: {}\n\
+                         send_delay: {}\nrecv_delay: {}\n\n",
+                        a,
+                        send_queue,
+                        recv_queue,
+                        send_buffer,
+                        rto,
+                        srtt,
+                        rttvar,
+                        una,
+                        rx_seq,
+                        send_delay,
+                        recv_delay
                     );
             }
 
@@ -105,7 +118,9 @@ fn main() {
 
     task::block_on(async move {
         let metrics = Arc::new(UcpListenerMetrics::new());
-        let ucp_listener = UcpListener::bind(&listen_addr, metrics.clone()).await;
+        let ucp_listener = UcpListener::bind(&listen_addr, metrics.clone())
+            .await
+            .unwrap();
         let tcp_listener = TcpListener::bind(&listen_addr).await.unwrap();
         let http_app = tide::with_state(metrics);
 
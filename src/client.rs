@@ -427,7 +427,14 @@ async fn ucp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
     core_tx: Sender<TunnelMsg>,
     ucp_metrics: Arc<UcpStreamMetrics>,
 ) {
-    let stream = UcpStream::connect(&server_addr, ucp_metrics).await;
+    let stream = match UcpStream::connect(&server_addr, ucp_metrics).await {
+        Ok(stream) => stream,
+
+        Err(_) => {
+            task::sleep(Duration::from_millis(1000)).await;
+            return;
+        }
+    };
 
     let mut port_hub = PortHub::new(tid);
     let (reader, writer) = &mut (&stream, &stream);
@@ -467,7 +474,7 @@ async fn process_tunnel_read<R: Read + Unpin>(
 
         let mut id = [0u8; 4];
         stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
+        let id = u32::from_be_bytes(id);
 
         match op {
             sc::CLOSE_PORT => {
@@ -481,7 +488,7 @@ async fn process_tunnel_read<R: Read + Unpin>(
             sc::CONNECT_OK | sc::DATA => {
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = u32::from_be_bytes(len);
 
                 let mut buf = vec![0; len as usize];
                 stream.read_exact(&mut buf).await?;
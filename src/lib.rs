@@ -77,18 +77,14 @@ mod protocol {
 
     fn write_cmd_id_len(buf: &mut [u8], cmd: u8, id: u32, len: u32) {
         buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
-            *(buf.as_ptr().offset(5) as *mut u32) = len.to_be();
-        }
+        buf[1..5].copy_from_slice(&id.to_be_bytes());
+        buf[5..9].copy_from_slice(&len.to_be_bytes());
     }
 
     fn pack_cmd_id_msg(cmd: u8, id: u32) -> [u8; 5] {
         let mut buf = [0u8; 5];
         buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
-        }
+        buf[1..5].copy_from_slice(&id.to_be_bytes());
         buf
     }
 
@@ -117,11 +113,7 @@ mod protocol {
 
         write_cmd_id_len(&mut buf, cs::CONNECT_DOMAIN_NAME, id, len);
         buf[9..buf_len - 2].copy_from_slice(domain);
-
-        unsafe {
-            let offset = (buf_len - 2) as isize;
-            *(buf.as_ptr().offset(offset) as *mut u16) = port.to_be();
-        }
+        buf[buf_len - 2..].copy_from_slice(&port.to_be_bytes());
 
         buf
     }
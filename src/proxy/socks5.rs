@@ -70,10 +70,10 @@ async fn handshake(stream: &mut TcpStream) -> std::io::Result<Destination> {
             let mut ipv4_addr = [0u8; 6];
             stream.read_exact(&mut ipv4_addr).await?;
 
-            let port = unsafe { *(ipv4_addr.as_ptr().offset(4) as *const u16) };
+            let port = u16::from_be_bytes([ipv4_addr[4], ipv4_addr[5]]);
             Destination::Address(SocketAddr::V4(SocketAddrV4::new(
                 Ipv4Addr::new(ipv4_addr[3], ipv4_addr[2], ipv4_addr[1], ipv4_addr[0]),
-                u16::from_be(port),
+                port,
             )))
         }
 
@@ -85,9 +85,9 @@ async fn handshake(stream: &mut TcpStream) -> std::io::Result<Destination> {
             let mut buf = vec![0u8; len + 2];
             stream.read_exact(&mut buf).await?;
 
-            let port = unsafe { *(buf.as_ptr().offset(len as isize) as *const u16) };
+            let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
             buf.truncate(len);
-            Destination::DomainName(buf, u16::from_be(port))
+            Destination::DomainName(buf, port)
         }
 
         ATYP_IPV6 => Destination::Unknown,
@@ -127,10 +127,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV4;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u32) = u32::from(ipv4.ip().clone()).to_be();
-                *(buf.as_ptr().offset(8) as *mut u16) = ipv4.port().to_be();
-            }
+            buf[4..8].copy_from_slice(&ipv4.ip().octets());
+            buf[8..10].copy_from_slice(&ipv4.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
@@ -142,10 +140,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV6;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u128) = u128::from(ipv6.ip().clone()).to_be();
-                *(buf.as_ptr().offset(20) as *mut u16) = ipv6.port().to_be();
-            }
+            buf[4..20].copy_from_slice(&ipv6.ip().octets());
+            buf[20..22].copy_from_slice(&ipv6.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
@@ -342,7 +342,7 @@ async fn process_tunnel_read<R: Read + Unpin>(
 
         let mut id = [0u8; 4];
         stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
+        let id = u32::from_be_bytes(id);
 
         match op {
             cs::OPEN_PORT => {
@@ -360,14 +360,14 @@ async fn process_tunnel_read<R: Read + Unpin>(
             cs::CONNECT_DOMAIN_NAME => {
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = u32::from_be_bytes(len);
 
                 let mut buf = vec![0; len as usize];
                 stream.read_exact(&mut buf).await?;
 
                 let pos = (len - 2) as usize;
                 let domain_name = decryptor.decrypt(&buf[0..pos]);
-                let port = u16::from_be(unsafe { *(buf[pos..].as_ptr() as *const u16) });
+                let port = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
 
                 let _ = sender
                     .send(TunnelMsg::CSConnectDN(id, domain_name, port))
@@ -377,7 +377,7 @@ async fn process_tunnel_read<R: Read + Unpin>(
             _ => {
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = u32::from_be_bytes(len);
 
                 let mut buf = vec![0; len as usize];
                 stream.read_exact(&mut buf).await?;
@@ -0,0 +1,133 @@
+use async_std::net::UdpSocket;
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use crate::ucp::{CongestionControlFn, UcpCryptoRef};
+
+// Coalesce the packets of an output tick into as few datagrams as possible
+pub const UCP_FEATURE_BATCH: u32 = 0x1;
+// Start from MIN_PACKET_SIZE and probe the path for larger datagrams
+pub const UCP_FEATURE_MTU_PROBE: u32 = 0x2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct UcpSample {
+    pub srtt: u32,
+    pub rttvar: u32,
+    pub rto: u32,
+    pub in_flight: usize,
+    pub window: u32,
+}
+
+pub type UcpSampleFn = Arc<dyn Fn(UcpSample) + Send + Sync>;
+
+pub type UcpAckedFn = Arc<dyn Fn(RangeInclusive<u32>) + Send + Sync>;
+
+pub type UcpCloseFn = Arc<dyn Fn(SocketAddr) + Send + Sync>;
+
+pub type AddressFilterFn = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+pub type SocketFactoryFn = Arc<dyn Fn(SocketAddr) -> io::Result<std::net::UdpSocket> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct UcpConfig {
+    // Skip CRC32 on trusted underlays, the checksum field is still reserved
+    pub skip_checksum: bool,
+    // Seals packet payloads, both peers need the same one
+    pub crypto: Option<UcpCryptoRef>,
+    // Packets sealed under one key before the stream moves what it sends
+    // to the next, UCP_REKEY_PACKETS when unset
+    pub rekey_packets: Option<u64>,
+    // Upper bound of unacked packets, on top of the remote window
+    pub max_in_flight: Option<usize>,
+    // Packets written but not yet sent before writes block, the remote
+    // window when unset
+    pub max_send_buffer: Option<usize>,
+    // Optional features offered in the handshake, only the ones both peers
+    // offer are enabled
+    pub features: u32,
+    // Opaque label sent in the SYN, readable by the server on accept
+    pub flow_label: Option<u32>,
+    // Millis after which unacked DATA is abandoned instead of resent, for
+    // streams which prefer latency over reliability
+    pub max_packet_age: Option<u32>,
+    // Resends of SYN or SYN_ACK before the handshake fails, left to the idle
+    // timeout when unset
+    pub max_syn_retries: Option<u32>,
+    // Acks of later packets after which a packet is resent without waiting
+    // for the RTO
+    pub fast_resend_times: Option<u32>,
+    // Window advertised to the peer and cap on our own in packets,
+    // DEFAULT_WINDOW when unset
+    pub recv_window: Option<u32>,
+    pub send_window: Option<u32>,
+    // Largest datagram sent, in UDP payload bytes, from MIN_PACKET_SIZE up
+    // to the UCP_PACKET_SIZE every peer can receive
+    pub mtu: Option<usize>,
+    // RTO in millis until the first RTT sample
+    pub initial_rto: Option<u32>,
+    // DATA smaller than this is held back until more is written, the stream
+    // is flushed or MIN_SEND_DELAY_MILLIS passed, against floods of tiny
+    // writes
+    pub min_send_size: Option<usize>,
+    // Clamp of the computed RTO in millis
+    pub min_rto: Option<u32>,
+    pub max_rto: Option<u32>,
+    // Packets per second allowed out of the socket, shared by all the streams
+    // of a listener, the rest is held back for later ticks
+    pub max_packet_rate: Option<u32>,
+    // Packets max_packet_rate lets out at once, one tick's worth when unset
+    pub packet_burst: Option<u32>,
+    // Millis a gap in the received data may block delivery before it is
+    // skipped and the data behind it is handed to the reader
+    pub recv_deadline: Option<u32>,
+    // Answer packets from unknown sessions with RST, at most this many per
+    // second, so peers of a restarted server reconnect instead of hanging
+    pub reset_unknown: Option<u32>,
+    // Millis in-order data may sit unread before the stream is reset, so a
+    // reader which stopped reading doesn't pin the session, no limit when
+    // unset
+    pub slow_reader_timeout: Option<u32>,
+    // Decides whether a SYN from the address may open a session, refused
+    // ones are answered like unknown sessions
+    pub address_filter: Option<AddressFilterFn>,
+    // Builds the congestion controller of each stream, no limit when unset
+    pub congestion_control: Option<CongestionControlFn>,
+    // Packets slow start grows cwnd to before congestion avoidance, until
+    // the first loss sets it, unlimited when unset
+    pub initial_ssthresh: Option<u32>,
+    // Called on every output tick with the control loop variables
+    pub on_sample: Option<UcpSampleFn>,
+    // Called with each run of consecutive seqs newly acked by the peer, under
+    // the stream lock so it must not call back into the stream
+    pub on_acked: Option<UcpAckedFn>,
+    // Called with the remote address once a FIN handshake closed the stream
+    pub on_close: Option<UcpCloseFn>,
+    // Millis of silence after which a heartbeat is sent, and after which the
+    // peer is considered dead, HEARTBEAT_INTERVAL_MILLIS and
+    // UCP_STREAM_BROKEN_MILLIS when unset
+    pub heartbeat_interval: Option<u32>,
+    pub idle_timeout: Option<u32>,
+    // Millis the peer may keep acking without advancing una before the
+    // stream fails as stalled, UCP_STREAM_STALLED_MILLIS when unset
+    pub stall_timeout: Option<u32>,
+    // Called with the remote address when the peer timed out
+    pub on_timeout: Option<UcpCloseFn>,
+    // Records every datagram the streams send and receive
+    #[cfg(feature = "pcap")]
+    pub capture: Option<Arc<crate::ucp::PcapWriter>>,
+    // Creates the socket for connect and bind, e.g. to set SO_REUSEPORT,
+    // instead of a plain bind of the address
+    pub socket_factory: Option<SocketFactoryFn>,
+}
+
+impl UcpConfig {
+    pub(super) async fn bind(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        match self.socket_factory {
+            Some(ref socket_factory) => socket_factory(addr).map(UdpSocket::from),
+            None => UdpSocket::bind(addr).await,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+pub trait CongestionControl: Send {
+    // Packets newly acked by the peer
+    fn on_ack(&mut self, packets: u32);
+    // Called once per tick with resends, timeout tells RTO from fast resend
+    fn on_loss(&mut self, timeout: bool);
+    fn on_rtt_sample(&mut self, rtt: u32);
+    // Packets allowed in flight
+    fn cwnd(&self) -> u32;
+}
+
+// Called with the initial ssthresh in packets
+pub type CongestionControlFn = Arc<dyn Fn(u32) -> Box<dyn CongestionControl> + Send + Sync>;
+
+pub struct NoCongestionControl;
+
+impl CongestionControl for NoCongestionControl {
+    fn on_ack(&mut self, _packets: u32) {}
+
+    fn on_loss(&mut self, _timeout: bool) {}
+
+    fn on_rtt_sample(&mut self, _rtt: u32) {}
+
+    fn cwnd(&self) -> u32 {
+        u32::MAX
+    }
+}
+
+// Packets a Reno stream may send before its first ack
+const RENO_INITIAL_CWND: u32 = 4;
+
+// TCP Reno in packets: slow start up to ssthresh, one packet per window of
+// acks after it, halving on fast resends and restarting on timeouts
+pub struct RenoCongestionControl {
+    cwnd: u32,
+    ssthresh: u32,
+    acked: u32,
+}
+
+impl RenoCongestionControl {
+    pub fn new(initial_ssthresh: u32) -> Self {
+        RenoCongestionControl {
+            cwnd: RENO_INITIAL_CWND,
+            ssthresh: initial_ssthresh,
+            acked: 0,
+        }
+    }
+}
+
+impl Default for RenoCongestionControl {
+    fn default() -> Self {
+        RenoCongestionControl::new(u32::MAX)
+    }
+}
+
+impl CongestionControl for RenoCongestionControl {
+    fn on_ack(&mut self, packets: u32) {
+        for _ in 0..packets {
+            if self.cwnd < self.ssthresh {
+                self.cwnd = self.cwnd.saturating_add(1);
+            } else {
+                self.acked += 1;
+                if self.acked >= self.cwnd {
+                    self.acked = 0;
+                    self.cwnd = self.cwnd.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    fn on_loss(&mut self, timeout: bool) {
+        self.ssthresh = (self.cwnd / 2).max(2);
+        self.cwnd = if timeout { 1 } else { self.ssthresh };
+        self.acked = 0;
+    }
+
+    fn on_rtt_sample(&mut self, _rtt: u32) {}
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reno_grows_then_backs_off() {
+        let mut reno = RenoCongestionControl::new(16);
+        assert_eq!(reno.cwnd(), RENO_INITIAL_CWND);
+
+        // Slow start doubles a window per window of acks
+        reno.on_ack(4);
+        assert_eq!(reno.cwnd(), 8);
+        reno.on_ack(8);
+        assert_eq!(reno.cwnd(), 16);
+
+        // Past ssthresh a whole window of acks adds one packet
+        reno.on_ack(15);
+        assert_eq!(reno.cwnd(), 16);
+        reno.on_ack(1);
+        assert_eq!(reno.cwnd(), 17);
+
+        // A fast resend halves it and stays in congestion avoidance
+        reno.on_loss(false);
+        assert_eq!(reno.cwnd(), 8);
+        reno.on_ack(8);
+        assert_eq!(reno.cwnd(), 9);
+
+        // A timeout restarts slow start from one packet
+        reno.on_loss(true);
+        assert_eq!(reno.cwnd(), 1);
+        reno.on_ack(3);
+        assert_eq!(reno.cwnd(), 4);
+        reno.on_ack(1);
+        assert_eq!(reno.cwnd(), 4);
+
+        // Never below two packets of ssthresh
+        reno.on_loss(true);
+        reno.on_loss(true);
+        reno.on_ack(2);
+        assert_eq!(reno.cwnd(), 2);
+    }
+}
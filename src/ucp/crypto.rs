@@ -0,0 +1,284 @@
+use crypto::chacha20::ChaCha20;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+
+use std::sync::Arc;
+
+pub type UcpKey = [u8; 32];
+
+// Seals the payload of every packet, authenticating the header with it.
+// The CRC32 still covers the whole packet in the clear
+pub trait UcpCrypto: Send + Sync {
+    // Bytes encrypt adds to a payload
+    fn overhead(&self) -> usize;
+    // Key sealing the packets of a session until its first rekey
+    fn session_key(&self, session_id: u32) -> UcpKey;
+    // Key a rekey moves to, key must not be computable from it. All keys
+    // still derive from the shared secret, a rekey limits what one key
+    // seals but gives no forward secrecy
+    fn next_key(&self, key: &UcpKey) -> UcpKey;
+    // Encrypts payload[..len] in place, payload has room for the overhead,
+    // returns the sealed length
+    fn encrypt(&self, key: &UcpKey, header: &[u8], payload: &mut [u8], len: usize) -> usize;
+    // Decrypts payload[..len] in place, None when it doesn't authenticate
+    fn decrypt(&self, key: &UcpKey, header: &[u8], payload: &mut [u8], len: usize)
+        -> Option<usize>;
+}
+
+pub type UcpCryptoRef = Arc<dyn UcpCrypto>;
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+// XChaCha20-Poly1305 under a key per session, derived from the SHA-256 of a
+// pre-shared secret. The nonce is random and sent in front of the tag, at
+// 192 bits it doesn't repeat by chance however many packets a key seals
+pub struct ChaChaPolyCrypto {
+    secret: UcpKey,
+}
+
+impl ChaChaPolyCrypto {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        let mut sha = Sha256::new();
+        sha.input(secret);
+        sha.result(&mut key);
+
+        ChaChaPolyCrypto { secret: key }
+    }
+}
+
+// The ChaCha20-Poly1305 construction of rust-crypto, on the XChaCha20 stream
+// whose subkey is derived from the first 16 bytes of the nonce
+fn xchacha_poly(key: &UcpKey, nonce: &[u8], header: &[u8]) -> (ChaCha20, Poly1305) {
+    let mut cipher = ChaCha20::new_xchacha20(key, nonce);
+    let mut mac_key = [0u8; 64];
+    cipher.process(&[0u8; 64], &mut mac_key);
+
+    let mut mac = Poly1305::new(&mac_key[..32]);
+    mac.input(header);
+    mac.input(&(header.len() as u64).to_le_bytes());
+
+    (cipher, mac)
+}
+
+impl UcpCrypto for ChaChaPolyCrypto {
+    fn overhead(&self) -> usize {
+        NONCE_SIZE + TAG_SIZE
+    }
+
+    fn session_key(&self, session_id: u32) -> UcpKey {
+        let mut key = [0u8; 32];
+        let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+        hmac.input(b"ucp session");
+        hmac.input(&session_id.to_be_bytes());
+        hmac.raw_result(&mut key);
+        key
+    }
+
+    fn next_key(&self, key: &UcpKey) -> UcpKey {
+        let mut next = [0u8; 32];
+        let mut hmac = Hmac::new(Sha256::new(), key);
+        hmac.input(b"ucp rekey");
+        hmac.raw_result(&mut next);
+        next
+    }
+
+    fn encrypt(&self, key: &UcpKey, header: &[u8], payload: &mut [u8], len: usize) -> usize {
+        let nonce = rand::random::<[u8; NONCE_SIZE]>();
+        let (mut cipher, mut mac) = xchacha_poly(key, &nonce, header);
+
+        let plain = payload[..len].to_vec();
+        let (sealed, rest) = payload.split_at_mut(len);
+        cipher.process(&plain, sealed);
+        mac.input(sealed);
+        mac.input(&(len as u64).to_le_bytes());
+
+        rest[..NONCE_SIZE].copy_from_slice(&nonce);
+        mac.raw_result(&mut rest[NONCE_SIZE..NONCE_SIZE + TAG_SIZE]);
+
+        len + NONCE_SIZE + TAG_SIZE
+    }
+
+    fn decrypt(
+        &self,
+        key: &UcpKey,
+        header: &[u8],
+        payload: &mut [u8],
+        len: usize,
+    ) -> Option<usize> {
+        let plain_len = len.checked_sub(NONCE_SIZE + TAG_SIZE)?;
+        let (sealed, rest) = payload[..len].split_at_mut(plain_len);
+        let (mut cipher, mut mac) = xchacha_poly(key, &rest[..NONCE_SIZE], header);
+
+        mac.input(sealed);
+        mac.input(&(plain_len as u64).to_le_bytes());
+        let mut tag = [0u8; TAG_SIZE];
+        mac.raw_result(&mut tag);
+
+        if !fixed_time_eq(&tag, &rest[NONCE_SIZE..]) {
+            return None;
+        }
+
+        let input = sealed.to_vec();
+        cipher.process(&input, sealed);
+        Some(plain_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::harness::*;
+    use crate::ucp::{UcpConfig, UCP_FEATURE_BATCH};
+
+    use async_std::task;
+    use std::time::Duration;
+
+    const HEADER: &[u8] = b"header";
+    const PLAIN: &[u8] = b"the payload of a packet";
+
+    fn sealed(crypto: &ChaChaPolyCrypto, key: &UcpKey) -> Vec<u8> {
+        let mut payload = PLAIN.to_vec();
+        payload.resize(PLAIN.len() + crypto.overhead(), 0);
+
+        let len = crypto.encrypt(key, HEADER, &mut payload, PLAIN.len());
+        assert_eq!(len, payload.len());
+        payload
+    }
+
+    fn opened(
+        crypto: &ChaChaPolyCrypto,
+        key: &UcpKey,
+        header: &[u8],
+        mut payload: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let len = payload.len();
+        let plain_len = crypto.decrypt(key, header, &mut payload, len)?;
+        payload.truncate(plain_len);
+        Some(payload)
+    }
+
+    #[test]
+    fn round_trip() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let key = crypto.session_key(1);
+
+        let payload = sealed(&crypto, &key);
+        assert_ne!(&payload[..PLAIN.len()], PLAIN);
+        assert_eq!(opened(&crypto, &key, HEADER, payload).unwrap(), PLAIN);
+
+        // An empty payload still carries a nonce and tag
+        let mut payload = vec![0u8; crypto.overhead()];
+        assert_eq!(crypto.encrypt(&key, HEADER, &mut payload, 0), payload.len());
+        assert_eq!(opened(&crypto, &key, HEADER, payload).unwrap(), b"");
+    }
+
+    #[test]
+    fn rekey_ratchets() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let key = crypto.session_key(1);
+        let next = crypto.next_key(&key);
+
+        assert_ne!(next, key);
+        assert_eq!(crypto.next_key(&key), next);
+        assert_ne!(crypto.next_key(&next), next);
+
+        let payload = sealed(&crypto, &next);
+        assert_eq!(opened(&crypto, &next, HEADER, payload).unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn nonces_differ() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let key = crypto.session_key(1);
+
+        let first = sealed(&crypto, &key);
+        let second = sealed(&crypto, &key);
+        assert_ne!(first[PLAIN.len()..], second[PLAIN.len()..]);
+        assert_ne!(first[..PLAIN.len()], second[..PLAIN.len()]);
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let payload = sealed(&crypto, &crypto.session_key(1));
+
+        // Another session, and the same session under another secret
+        let other_session = crypto.session_key(2);
+        assert!(opened(&crypto, &other_session, HEADER, payload.clone()).is_none());
+
+        let next = crypto.next_key(&crypto.session_key(1));
+        assert!(opened(&crypto, &next, HEADER, payload.clone()).is_none());
+
+        let other_secret = ChaChaPolyCrypto::new(b"secret2").session_key(1);
+        assert_ne!(other_secret, crypto.session_key(1));
+        assert!(opened(&crypto, &other_secret, HEADER, payload).is_none());
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let key = crypto.session_key(1);
+        let payload = sealed(&crypto, &key);
+
+        assert!(opened(&crypto, &key, b"Header", payload.clone()).is_none());
+
+        for i in 0..payload.len() {
+            let mut tampered = payload.clone();
+            tampered[i] ^= 0x01;
+            assert!(opened(&crypto, &key, HEADER, tampered).is_none());
+        }
+
+        let mut short = vec![0u8; crypto.overhead() - 1];
+        assert!(crypto
+            .decrypt(&key, HEADER, &mut short, crypto.overhead() - 1)
+            .is_none());
+    }
+
+    fn sealed_config(secret: &[u8]) -> UcpConfig {
+        UcpConfig {
+            crypto: Some(Arc::new(ChaChaPolyCrypto::new(secret))),
+            features: UCP_FEATURE_BATCH,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sealed_session() {
+        task::block_on(async {
+            let harness = TestHarness::new(sealed_config(b"secret"));
+            let (client, server) = harness.establish(sealed_config(b"secret")).await;
+            assert_eq!(client.features(), UCP_FEATURE_BATCH);
+
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_drop_rate(0.05);
+                link.set_delay(Duration::from_millis(5), Duration::from_millis(10));
+            }
+
+            let sent = pattern(3, 1 << 20);
+            let received = transfer(&client, &server, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            let received = transfer(&server, &client, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+        });
+    }
+
+    #[test]
+    fn other_secret_never_connects() {
+        task::block_on(async {
+            let harness = TestHarness::new(sealed_config(b"secret"));
+            let client = harness.connect(sealed_config(b"other"));
+
+            let established =
+                wait_until(Duration::from_secs(1), || client.inner.is_established()).await;
+            assert!(!established);
+        });
+    }
+}
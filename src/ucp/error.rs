@@ -0,0 +1,39 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UcpError {
+    Rejected(u32),
+    Stalled,
+    TimedOut,
+    SlowReader,
+    HandshakeTimedOut,
+}
+
+impl fmt::Display for UcpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UcpError::Rejected(reason) => write!(f, "rejected by peer, reason: {}", reason),
+            UcpError::Stalled => write!(f, "peer stopped advancing una"),
+            UcpError::TimedOut => write!(f, "peer stopped answering heartbeats"),
+            UcpError::SlowReader => write!(f, "received data left unread"),
+            UcpError::HandshakeTimedOut => write!(f, "peer never completed the handshake"),
+        }
+    }
+}
+
+impl std::error::Error for UcpError {}
+
+impl From<UcpError> for Error {
+    fn from(e: UcpError) -> Self {
+        let kind = match e {
+            UcpError::Rejected(_) => ErrorKind::ConnectionRefused,
+            UcpError::Stalled
+            | UcpError::TimedOut
+            | UcpError::SlowReader
+            | UcpError::HandshakeTimedOut => ErrorKind::TimedOut,
+        };
+
+        Error::new(kind, e)
+    }
+}
@@ -0,0 +1,110 @@
+use async_std::io::{self, ReadExt, WriteExt};
+
+use std::io::{Error, ErrorKind};
+
+use crate::ucp::UcpStream;
+
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct FramedUcp {
+    stream: UcpStream,
+}
+
+impl FramedUcp {
+    pub fn new(stream: UcpStream) -> Self {
+        FramedUcp { stream }
+    }
+
+    pub fn get_ref(&self) -> &UcpStream {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> UcpStream {
+        self.stream
+    }
+
+    pub async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "frame too large"));
+        }
+
+        let mut stream = &self.stream;
+        stream
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(frame).await
+    }
+
+    pub async fn recv_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut stream = &self.stream;
+        let mut len = [0u8; 4];
+
+        let n = stream.read(&mut len).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        stream.read_exact(&mut len[n..]).await?;
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "frame too large"));
+        }
+
+        let mut frame = vec![0u8; len];
+        stream.read_exact(&mut frame).await?;
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::harness::*;
+    use crate::ucp::UcpConfig;
+
+    use async_std::task;
+    use futures::future::join;
+
+    #[test]
+    fn frames_round_trip() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            let mut sender = FramedUcp::new(client);
+            let mut receiver = FramedUcp::new(server);
+
+            // Empty, smaller and larger than a packet, and spanning many
+            let frames: Vec<_> = [0, 1, 3, 1000, 1500, 4096, 100_000, 1 << 20]
+                .iter()
+                .enumerate()
+                .map(|(seed, &len)| pattern(seed as u64, len))
+                .collect();
+
+            let (sent, received) = join(
+                async {
+                    for frame in frames.iter() {
+                        sender.send_frame(frame).await?;
+                    }
+                    Ok::<_, Error>(())
+                },
+                async {
+                    let mut received = Vec::new();
+                    for _ in 0..frames.len() {
+                        received.push(receiver.recv_frame().await?);
+                    }
+                    Ok::<_, Error>(received)
+                },
+            )
+            .await;
+            sent.unwrap();
+
+            for (frame, received) in frames.iter().zip(received.unwrap()) {
+                assert_eq!(received.as_ref(), Some(frame));
+            }
+
+            let oversized = vec![0u8; MAX_FRAME_SIZE + 1];
+            let err = sender.send_frame(&oversized).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        });
+    }
+}
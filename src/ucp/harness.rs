@@ -0,0 +1,234 @@
+use async_std::channel::{unbounded, Receiver};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::task;
+use futures::future::join;
+
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ucp::{
+    LoopbackTransport, UcpConfig, UcpListener, UcpListenerMetrics, UcpStream, UcpStreamMetrics,
+};
+
+pub(super) const CLIENT_ADDR: &str = "10.0.0.1:1000";
+pub(super) const SERVER_ADDR: &str = "10.0.0.2:2000";
+
+// A listener and the clients connecting to it over an in-memory link, each
+// direction of which can be told to drop, delay or reorder datagrams
+pub(super) struct TestHarness {
+    pub(super) client_link: Arc<LoopbackTransport>,
+    pub(super) server_link: Arc<LoopbackTransport>,
+    accepted: Receiver<UcpStream>,
+    listener_task: Option<task::JoinHandle<()>>,
+}
+
+impl TestHarness {
+    pub(super) fn new(server_config: UcpConfig) -> Self {
+        let (client_link, server_link) =
+            LoopbackTransport::pair(CLIENT_ADDR.parse().unwrap(), SERVER_ADDR.parse().unwrap());
+        let mut listener = UcpListener::from_transport(
+            server_link.clone(),
+            Arc::new(UcpListenerMetrics::new()),
+            server_config,
+        );
+
+        // The listener only handles datagrams while incoming is polled
+        let (sender, accepted) = unbounded();
+        let listener_task = task::spawn(async move {
+            loop {
+                let stream = listener.incoming().await;
+                if sender.send(stream).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        TestHarness {
+            client_link,
+            server_link,
+            accepted,
+            listener_task: Some(listener_task),
+        }
+    }
+
+    pub(super) fn connect(&self, config: UcpConfig) -> UcpStream {
+        UcpStream::from_transport(
+            self.client_link.clone(),
+            server_addr(),
+            Arc::new(UcpStreamMetrics::new()),
+            config,
+        )
+    }
+
+    pub(super) async fn accept(&self) -> UcpStream {
+        self.accepted.recv().await.unwrap()
+    }
+
+    // A client and the server side of its session, both established
+    pub(super) async fn establish(&self, config: UcpConfig) -> (UcpStream, UcpStream) {
+        let client = self.connect(config);
+        let server = self.accept().await;
+
+        let established = wait_until(Duration::from_secs(5), || {
+            client.inner.is_established() && server.inner.is_established()
+        })
+        .await;
+        assert!(established, "handshake timed out");
+
+        (client, server)
+    }
+}
+
+impl Drop for TestHarness {
+    fn drop(&mut self) {
+        if let Some(listener_task) = self.listener_task.take() {
+            task::spawn(async move {
+                listener_task.cancel().await;
+            });
+        }
+    }
+}
+
+pub(super) fn client_addr() -> SocketAddr {
+    CLIENT_ADDR.parse().unwrap()
+}
+
+pub(super) fn server_addr() -> SocketAddr {
+    SERVER_ADDR.parse().unwrap()
+}
+
+// Checks cond every output tick until it holds, false if it still doesn't
+// after timeout
+pub(super) async fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut cond: F) -> bool {
+    let start = Instant::now();
+
+    while !cond() {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        task::sleep(Duration::from_millis(10)).await;
+    }
+
+    true
+}
+
+// Reproducible pseudo-random bytes, so what arrives can be checked byte for
+// byte against what was sent
+pub(super) fn pattern(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed | 1;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect()
+}
+
+// Offset of the first byte received differs from sent, a short or long
+// receive diverges where the shorter one ends
+pub(super) fn first_divergence(sent: &[u8], received: &[u8]) -> Option<usize> {
+    let diverged = sent.iter().zip(received.iter()).position(|(a, b)| a != b);
+
+    match diverged {
+        Some(offset) => Some(offset),
+        None if sent.len() != received.len() => Some(min(sent.len(), received.len())),
+        None => None,
+    }
+}
+
+// Writes data to one stream while reading as much from the other, a writer
+// alone would block once the send buffer is full
+pub(super) async fn transfer(from: &UcpStream, to: &UcpStream, data: &[u8]) -> Vec<u8> {
+    let mut received = vec![0u8; data.len()];
+
+    let (written, read) = join(
+        async {
+            let mut writer = from;
+            writer.write_all(data).await
+        },
+        async {
+            let mut reader = to;
+            reader.read_exact(&mut received).await
+        },
+    )
+    .await;
+    written.unwrap();
+    read.unwrap();
+
+    received
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_and_transfer() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            harness
+                .client_link
+                .set_delay(Duration::from_millis(20), Duration::from_millis(0));
+            harness
+                .server_link
+                .set_delay(Duration::from_millis(20), Duration::from_millis(0));
+
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+
+            assert_eq!(client.session_id(), server.session_id());
+            assert_eq!(client.remote_addr(), server_addr());
+            assert_eq!(server.remote_addr(), client_addr());
+
+            (&client).write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            (&server).read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+
+            (&server).write_all(b"pong").await.unwrap();
+            (&client).read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        });
+    }
+
+    #[test]
+    fn divergence() {
+        let sent = pattern(7, 1000);
+        assert_eq!(sent, pattern(7, 1000));
+        assert_ne!(sent, pattern(8, 1000));
+
+        let mut received = sent.clone();
+        assert_eq!(first_divergence(&sent, &received), None);
+        assert_eq!(first_divergence(&sent, &received[..600]), Some(600));
+
+        received[321] ^= 0xff;
+        assert_eq!(first_divergence(&sent, &received), Some(321));
+    }
+
+    #[test]
+    fn ordered_delivery_over_lossy_link() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_drop_rate(0.05);
+                link.set_delay(Duration::from_millis(5), Duration::from_millis(10));
+            }
+
+            let sent = pattern(1, 4 << 20);
+            let received = transfer(&client, &server, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            let sent = pattern(2, 1 << 20);
+            let received = transfer(&server, &client, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            assert!(client.metrics().get_resent_packets() > 0);
+        });
+    }
+}
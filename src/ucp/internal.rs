@@ -1,5 +1,3 @@
-use async_std::net::UdpSocket;
-
 use crossbeam_utils::Backoff;
 use rand::random;
 
@@ -7,12 +5,13 @@ use std::cell::Cell;
 use std::cmp::min;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+use crate::ucp::limiter::*;
 use crate::ucp::packet::*;
 use crate::ucp::*;
 
@@ -24,7 +23,26 @@ pub struct UcpStreamMetrics {
     rto: AtomicU32,
     srtt: AtomicU32,
     rttvar: AtomicU32,
+    min_rtt: AtomicU32,
+    rtt_inflation: AtomicU32,
+    cwnd: AtomicU32,
     rx_seq: AtomicU32,
+    send_delay: AtomicI32,
+    recv_delay: AtomicI32,
+    abandoned: AtomicUsize,
+    skipped: AtomicUsize,
+    truncated: AtomicUsize,
+    throttled: AtomicUsize,
+    reorder_delay: AtomicU32,
+    max_reorder_delay: AtomicU32,
+    sent_bytes: AtomicU64,
+    delivered_bytes: AtomicU64,
+    recv_bytes: AtomicU64,
+    sent_packets: AtomicU64,
+    recv_packets: AtomicU64,
+    resent_packets: AtomicU64,
+    dup_acks: AtomicU64,
+    path_mtu: AtomicUsize,
 }
 
 impl UcpStreamMetrics {
@@ -37,7 +55,26 @@ impl UcpStreamMetrics {
             rto: AtomicU32::new(0),
             srtt: AtomicU32::new(0),
             rttvar: AtomicU32::new(0),
+            min_rtt: AtomicU32::new(0),
+            rtt_inflation: AtomicU32::new(0),
+            cwnd: AtomicU32::new(0),
             rx_seq: AtomicU32::new(0),
+            send_delay: AtomicI32::new(0),
+            recv_delay: AtomicI32::new(0),
+            abandoned: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            truncated: AtomicUsize::new(0),
+            throttled: AtomicUsize::new(0),
+            reorder_delay: AtomicU32::new(0),
+            max_reorder_delay: AtomicU32::new(0),
+            sent_bytes: AtomicU64::new(0),
+            delivered_bytes: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+            sent_packets: AtomicU64::new(0),
+            recv_packets: AtomicU64::new(0),
+            resent_packets: AtomicU64::new(0),
+            dup_acks: AtomicU64::new(0),
+            path_mtu: AtomicUsize::new(0),
         }
     }
 
@@ -69,29 +106,148 @@ impl UcpStreamMetrics {
         self.rttvar.load(Ordering::Relaxed)
     }
 
+    pub fn get_min_rtt(&self) -> u32 {
+        self.min_rtt.load(Ordering::Relaxed)
+    }
+
+    // srtt over min_rtt in percent, well above 100 means the path is queueing
+    pub fn get_rtt_inflation(&self) -> u32 {
+        self.rtt_inflation.load(Ordering::Relaxed)
+    }
+
+    // u32::MAX without congestion control
+    pub fn get_cwnd(&self) -> u32 {
+        self.cwnd.load(Ordering::Relaxed)
+    }
+
     pub fn get_rx_seq(&self) -> u32 {
         self.rx_seq.load(Ordering::Relaxed)
     }
+
+    pub fn get_send_delay(&self) -> i32 {
+        self.send_delay.load(Ordering::Relaxed)
+    }
+
+    pub fn get_recv_delay(&self) -> i32 {
+        self.recv_delay.load(Ordering::Relaxed)
+    }
+
+    pub fn get_abandoned(&self) -> usize {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+
+    pub fn get_skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    pub fn get_truncated(&self) -> usize {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn get_throttled(&self) -> usize {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Millis received packets waited behind a gap before delivery, smoothed
+    pub fn get_reorder_delay(&self) -> u32 {
+        self.reorder_delay.load(Ordering::Relaxed)
+    }
+
+    pub fn get_max_reorder_delay(&self) -> u32 {
+        self.max_reorder_delay.load(Ordering::Relaxed)
+    }
+
+    // Payload bytes put on the wire, including retransmissions
+    pub fn get_sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    // Unique payload bytes acked by the peer
+    pub fn get_delivered_bytes(&self) -> u64 {
+        self.delivered_bytes.load(Ordering::Relaxed)
+    }
+
+    // DATA payload bytes received, including duplicates
+    pub fn get_recv_bytes(&self) -> u64 {
+        self.recv_bytes.load(Ordering::Relaxed)
+    }
+
+    // Packets of any command, batches count each packet they carry
+    pub fn get_sent_packets(&self) -> u64 {
+        self.sent_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn get_recv_packets(&self) -> u64 {
+        self.recv_packets.load(Ordering::Relaxed)
+    }
+
+    pub fn get_resent_packets(&self) -> u64 {
+        self.resent_packets.load(Ordering::Relaxed)
+    }
+
+    // Acks of packets already acked or never sent
+    pub fn get_dup_acks(&self) -> u64 {
+        self.dup_acks.load(Ordering::Relaxed)
+    }
+
+    // Largest datagram known to reach the peer
+    pub fn get_path_mtu(&self) -> usize {
+        self.path_mtu.load(Ordering::Relaxed)
+    }
+}
+
+// The limit holding back data, checked in this order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UcpSendLimit {
+    // Writes wait for room in the send buffer
+    SendBuffer,
+    // Buffered packets wait for max_in_flight
+    InFlight,
+    // Buffered packets wait for the peer's window
+    Window,
+    // Buffered packets wait for the congestion window
+    Congestion,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum UcpState {
     NONE,
     ACCEPTING,
     CONNECTING,
     ESTABLISHED,
+    CLOSING,
+    CLOSED,
 }
 
 pub(super) struct InnerStream {
-    pub(super) socket: Arc<UdpSocket>,
+    pub(super) socket: UcpTransportRef,
+    config: Cell<UcpConfig>,
+    limiter: Option<Arc<RateLimiter>>,
     lock: AtomicUsize,
     alive: AtomicBool,
+    // Dropped by its owner, the next output tick resets the peer
+    reset_pending: Cell<bool>,
+    error: Cell<Option<UcpError>>,
     metrics: Arc<UcpStreamMetrics>,
-    remote_addr: SocketAddr,
+    remote_addr: Cell<SocketAddr>,
     initial_time: Instant,
     alive_time: Cell<Instant>,
+    progress_time: Cell<Instant>,
+    // ACKs received since una last moved, a silent peer is a dead link
+    // rather than a stalled one
+    stalled_acks: Cell<u32>,
+    read_time: Cell<Instant>,
+    gap_time: Cell<Option<Instant>>,
     heartbeat: Cell<Instant>,
     state: Cell<UcpState>,
+    migrate_probe: Cell<Option<(SocketAddr, u32, Instant)>>,
+    flushing: Cell<bool>,
+    fin_sent: Cell<bool>,
+    fin_received: Cell<bool>,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>,
+    read_deadline: Cell<Option<Instant>>,
+    write_deadline: Cell<Option<Instant>>,
 
     send_queue: Cell<UcpPacketQueue>,
     recv_queue: Cell<UcpPacketQueue>,
@@ -102,13 +258,42 @@ pub(super) struct InnerStream {
 
     ack_list: Cell<Vec<(u32, u32)>>,
     session_id: Cell<u32>,
+    // Crypto key of the last session a packet was sealed or opened for
+    session_key: Cell<Option<(u32, UcpKey)>>,
+    // Rekeys of what we send and of what the peer sends, with the keys
+    // past the session key. The previous one is kept while packets it
+    // sealed may still be resent
+    send_epoch: Cell<u32>,
+    send_key: Cell<Option<UcpKey>>,
+    retiring_key: Cell<Option<UcpKey>>,
+    sealed_packets: Cell<u64>,
+    recv_epoch: Cell<u32>,
+    recv_key: Cell<Option<UcpKey>>,
+    retiring_recv_key: Cell<Option<UcpKey>>,
+    features: Cell<u32>,
+    path_mtu: Cell<usize>,
+    mtu_probe: Cell<Option<(usize, u32)>>,
+    mtu_probe_ceiling: Cell<usize>,
+    mtu_probe_losses: Cell<u32>,
+    flow_label: Cell<Option<u32>>,
     local_window: Cell<u32>,
     remote_window: Cell<u32>,
     seq: Cell<u32>,
     una: Cell<u32>,
+    remote_una: Cell<u32>,
     rto: Cell<u32>,
     srtt: Cell<u32>,
     rttvar: Cell<u32>,
+    min_rtt: Cell<Option<u32>>,
+    delivered: Cell<u64>,
+    sacked: Cell<Vec<(u32, u32)>>,
+    delivery_sample: Cell<(Instant, u64)>,
+    delivery_rate: Cell<Option<u64>>,
+    send_delay: Cell<Option<i32>>,
+    recv_delay: Cell<Option<i32>>,
+    rto_override: Cell<Option<u32>>,
+    cwnd_override: Cell<Option<u32>>,
+    congestion: Cell<Box<dyn CongestionControl>>,
 }
 
 unsafe impl Send for InnerStream {}
@@ -127,20 +312,53 @@ impl Drop for Lock<'_> {
 
 impl InnerStream {
     pub(super) fn new(
-        socket: Arc<UdpSocket>,
+        socket: UcpTransportRef,
         remote_addr: SocketAddr,
         metrics: Arc<UcpStreamMetrics>,
+        config: UcpConfig,
+        limiter: Option<Arc<RateLimiter>>,
     ) -> Self {
+        let congestion = match config.congestion_control {
+            Some(ref congestion_control) => {
+                congestion_control(config.initial_ssthresh.unwrap_or(u32::MAX))
+            }
+            None => Box::new(NoCongestionControl),
+        };
+
+        let local_window = config.recv_window.unwrap_or(DEFAULT_WINDOW);
+        let path_mtu = if config.features & UCP_FEATURE_MTU_PROBE != 0 {
+            MIN_PACKET_SIZE
+        } else {
+            UCP_PACKET_SIZE
+        };
+        let rto = config.initial_rto.unwrap_or(DEFAULT_RTO);
+
         InnerStream {
             socket: socket,
+            config: Cell::new(config),
+            limiter: limiter,
             lock: AtomicUsize::new(0),
             alive: AtomicBool::new(true),
+            reset_pending: Cell::new(false),
+            error: Cell::new(None),
             metrics: metrics,
-            remote_addr: remote_addr,
+            remote_addr: Cell::new(remote_addr),
             initial_time: Instant::now(),
             alive_time: Cell::new(Instant::now()),
+            progress_time: Cell::new(Instant::now()),
+            stalled_acks: Cell::new(0),
+            read_time: Cell::new(Instant::now()),
+            gap_time: Cell::new(None),
             heartbeat: Cell::new(Instant::now()),
             state: Cell::new(UcpState::NONE),
+            migrate_probe: Cell::new(None),
+            flushing: Cell::new(false),
+            fin_sent: Cell::new(false),
+            fin_received: Cell::new(false),
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None),
+            read_deadline: Cell::new(None),
+            write_deadline: Cell::new(None),
 
             send_queue: Cell::new(UcpPacketQueue::new()),
             recv_queue: Cell::new(UcpPacketQueue::new()),
@@ -151,26 +369,70 @@ impl InnerStream {
 
             ack_list: Cell::new(Vec::new()),
             session_id: Cell::new(0),
-            local_window: Cell::new(DEFAULT_WINDOW),
+            session_key: Cell::new(None),
+            send_epoch: Cell::new(0),
+            send_key: Cell::new(None),
+            retiring_key: Cell::new(None),
+            sealed_packets: Cell::new(0),
+            recv_epoch: Cell::new(0),
+            recv_key: Cell::new(None),
+            retiring_recv_key: Cell::new(None),
+            features: Cell::new(0),
+            path_mtu: Cell::new(path_mtu),
+            mtu_probe: Cell::new(None),
+            mtu_probe_ceiling: Cell::new(UCP_PACKET_SIZE),
+            mtu_probe_losses: Cell::new(0),
+            flow_label: Cell::new(None),
+            local_window: Cell::new(local_window),
             remote_window: Cell::new(DEFAULT_WINDOW),
             seq: Cell::new(0),
             una: Cell::new(0),
-            rto: Cell::new(DEFAULT_RTO),
+            remote_una: Cell::new(0),
+            rto: Cell::new(rto),
             srtt: Cell::new(0),
             rttvar: Cell::new(0),
+            min_rtt: Cell::new(None),
+            delivered: Cell::new(0),
+            sacked: Cell::new(Vec::new()),
+            delivery_sample: Cell::new((Instant::now(), 0)),
+            delivery_rate: Cell::new(None),
+            send_delay: Cell::new(None),
+            recv_delay: Cell::new(None),
+            rto_override: Cell::new(None),
+            cwnd_override: Cell::new(None),
+            congestion: Cell::new(congestion),
         }
     }
 
     pub(super) async fn input(&self, packet: Box<UcpPacket>, remote_addr: SocketAddr) {
-        if self.remote_addr != remote_addr {
-            error!(
-                "unexpect packet from {}, expect from {}",
-                remote_addr, self.remote_addr
-            );
-            return;
+        let _l = self.lock();
+
+        if self.remote_addr.get() != remote_addr {
+            if !self.is_migrate_answer(&packet, remote_addr) {
+                error!(
+                    "unexpect packet from {}, expect from {}",
+                    remote_addr,
+                    self.remote_addr.get()
+                );
+                return;
+            }
+
+            self.migrated(remote_addr);
         }
 
-        let _l = self.lock();
+        self.check_peer_rekeyed(&packet);
+
+        if packet.cmd == CMD_BATCH {
+            for packet in self.unbatch(packet).into_iter() {
+                self.input_packet(packet).await;
+            }
+        } else {
+            self.input_packet(packet).await;
+        }
+    }
+
+    async fn input_packet(&self, packet: Box<UcpPacket>) {
+        self.metrics.recv_packets.fetch_add(1, Ordering::Relaxed);
 
         let state = self.state.get();
         match state {
@@ -181,6 +443,9 @@ impl InnerStream {
                     error!("not syn packet in UcpState::NONE");
                 }
             }
+            UcpState::CONNECTING if packet.is_syn() => {
+                self.simultaneous_open(packet);
+            }
             _ => {
                 self.processing(packet).await;
             }
@@ -190,13 +455,35 @@ impl InnerStream {
     pub(super) async fn output(&self) {
         let _l = self.lock();
 
-        if self.check_if_alive() {
-            self.do_heartbeat().await;
-            self.send_ack_list().await;
-            self.timeout_resend().await;
-            self.send_pending_packets().await;
-        } else {
+        if self.reset_pending.get() {
+            let mut rst = self.new_noseq_packet(CMD_RST);
+            self.send_packet_directly(&mut rst).await;
+            self.die();
+        } else if !self.check_if_alive() {
+            self.error.set(Some(UcpError::TimedOut));
+            self.die();
+
+            if let Some(ref on_timeout) = self.config().on_timeout {
+                on_timeout(self.remote_addr.get());
+            }
+        } else if self.check_if_stalled() {
+            self.error.set(Some(UcpError::Stalled));
+            self.die();
+        } else if self.check_if_handshake_failed() {
+            self.error.set(Some(UcpError::HandshakeTimedOut));
             self.die();
+        } else if self.check_if_slow_reader() {
+            let mut rst = self.new_noseq_packet(CMD_RST);
+            self.send_packet_directly(&mut rst).await;
+            self.error.set(Some(UcpError::SlowReader));
+            self.die();
+        } else {
+            self.check_recv_deadline();
+            self.check_rekey();
+            self.transmit().await;
+            self.try_wake_writer();
+            self.check_io_deadlines();
+            self.check_if_closed().await;
         }
 
         self.update_metrics();
@@ -207,210 +494,1131 @@ impl InnerStream {
         cx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
+        self.do_read(Some(cx.waker()), buf)
+    }
+
+    pub(super) fn try_read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.do_read(None, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(Error::from(ErrorKind::WouldBlock)),
+        }
+    }
+
+    pub(super) fn poll_write(&self, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.do_write(Some(cx.waker()), buf)
+    }
+
+    pub(super) fn try_write(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.do_write(None, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(Error::from(ErrorKind::WouldBlock)),
+        }
+    }
+
+    pub(super) fn flush(&self) {
         let _l = self.lock();
+        self.flushing.set(true);
+    }
 
-        if !self.alive() {
-            return Poll::Ready(Err(Error::from(ErrorKind::Other)));
+    pub(super) fn try_flush(&self) -> std::io::Result<()> {
+        let _l = self.lock();
+        self.flushing.set(true);
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        if send_queue.is_empty() && send_buffer.is_empty() {
+            Ok(())
+        } else if !self.alive() {
+            Err(self.dead_error())
+        } else {
+            Err(Error::from(ErrorKind::WouldBlock))
+        }
+    }
+
+    fn do_read(&self, waker: Option<&Waker>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let _l = self.lock();
+        let closed = self.state.get() == UcpState::CLOSED;
+
+        if !self.alive() && !closed {
+            return Poll::Ready(Err(self.dead_error()));
         }
 
+        // The peer's FIN is in order, nothing more is coming
         let n = self.recv(buf);
-        if n == 0 {
-            self.read_waker.set(Some(cx.waker().clone()));
+        if n == 0 && (closed || self.fin_received.get()) {
+            self.read_deadline.set(None);
+            Poll::Ready(Ok(0))
+        } else if n == 0 {
+            if self.is_io_timed_out(self.read_timeout.get(), &self.read_deadline) {
+                return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+            }
+            if let Some(waker) = waker {
+                self.read_waker.set(Some(waker.clone()));
+            }
             Poll::Pending
         } else {
+            self.read_deadline.set(None);
             Poll::Ready(Ok(n))
         }
     }
 
-    pub(super) fn poll_write(&self, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    fn do_write(&self, waker: Option<&Waker>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         let _l = self.lock();
 
+        // Only our own shutdown ends our half, the peer's FIN ends its half
+        if self.fin_sent.get() || self.state.get() == UcpState::CLOSED {
+            return Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe)));
+        }
+
         if !self.alive() {
-            return Poll::Ready(Err(Error::from(ErrorKind::Other)));
+            return Poll::Ready(Err(self.dead_error()));
         }
 
         if self.is_send_buffer_overflow() {
-            self.write_waker.set(Some(cx.waker().clone()));
+            if self.is_io_timed_out(self.write_timeout.get(), &self.write_deadline) {
+                return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+            }
+            if let Some(waker) = waker {
+                self.write_waker.set(Some(waker.clone()));
+            }
             Poll::Pending
         } else {
-            self.send(buf);
-            Poll::Ready(Ok(buf.len()))
+            self.write_deadline.set(None);
+            Poll::Ready(Ok(self.send(buf)))
         }
     }
 
     pub(super) fn shutdown(&self) {
         info!(
             "shutdown {}, session: {}",
-            self.remote_addr,
+            self.remote_addr.get(),
             self.session_id.get()
         );
         let _l = self.lock();
-        self.die();
-    }
 
-    pub(super) fn alive(&self) -> bool {
-        self.alive.load(Ordering::Relaxed)
+        // Established streams queue a FIN behind the unsent data and close
+        // once it is acked, the others have nothing to flush
+        match self.state.get() {
+            UcpState::ESTABLISHED => {
+                self.send_fin();
+                self.set_state(UcpState::CLOSING, "shutdown");
+            }
+            UcpState::CLOSING | UcpState::CLOSED => {}
+            _ => self.die(),
+        }
     }
 
-    fn die(&self) {
-        self.alive.store(false, Ordering::Relaxed);
+    pub(super) async fn reject(&self, reason: u32) {
+        info!(
+            "reject {}, session: {}, reason: {}",
+            self.remote_addr.get(),
+            self.session_id.get(),
+            reason
+        );
+        let _l = self.lock();
 
-        if let Some(w) = self.read_waker.take() {
-            w.wake()
-        }
+        let mut rst = self.new_noseq_packet(CMD_RST);
+        rst.payload_write_u32(reason);
+        self.send_packet_directly(&mut rst).await;
+        self.die();
+    }
 
-        if let Some(w) = self.write_waker.take() {
-            w.wake()
+    pub(super) async fn abort(&self) {
+        let _l = self.lock();
+
+        if !self.alive() {
+            return;
         }
-    }
 
-    fn lock(&self) -> Lock<'_> {
-        let backoff = Backoff::new();
-        while self.lock.compare_and_swap(0, 1, Ordering::Acquire) != 0 {
-            backoff.snooze();
+        match self.state.get() {
+            UcpState::NONE => {}
+            _ => {
+                let mut rst = self.new_noseq_packet(CMD_RST);
+                self.send_packet_directly(&mut rst).await;
+            }
         }
-        Lock { inner: self }
-    }
 
-    fn unlock(&self) {
-        self.lock.store(0, Ordering::SeqCst);
+        info!(
+            "abort {}, session: {}",
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+        self.die();
     }
 
-    fn recv(&self, buf: &mut [u8]) -> usize {
-        let mut size = 0;
-        let una = self.una.get();
-        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
-
-        while size < buf.len() && !recv_queue.is_empty() {
-            if let Some(packet) = recv_queue.front_mut() {
-                let diff = (packet.seq - una) as i32;
-                if diff >= 0 {
-                    break;
-                }
+    // Nobody reads or writes the stream once its owner is dropped, only a
+    // shutdown in flight, or one of a stream the peer already closed, is
+    // left to finish the FIN exchange. Anything else is reset like abort
+    // does, by the send task since a drop can't wait on the socket. True
+    // while the FIN exchange still needs the receiver
+    pub(super) fn release(&self) -> bool {
+        let _l = self.lock();
 
-                size += packet.payload_read_slice(&mut buf[size..]);
-            }
+        if !self.alive() {
+            return false;
+        }
 
-            let no_remain_payload = recv_queue
-                .front()
-                .map(|packet| packet.payload_remaining() == 0)
-                .unwrap();
+        info!(
+            "release {}, session: {}",
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
 
-            if no_remain_payload {
-                recv_queue.pop_front();
+        match self.state.get() {
+            UcpState::CLOSING => return true,
+            UcpState::ESTABLISHED if self.fin_received.get() => {
+                self.send_fin();
+                self.set_state(UcpState::CLOSING, "release");
+                return true;
             }
+            UcpState::NONE => self.die(),
+            _ => self.reset_pending.set(true),
         }
 
-        size
+        false
     }
 
-    fn send(&self, buf: &[u8]) {
-        let mut pos = 0;
-        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+    pub(super) async fn flush_acks(&self) {
+        let _l = self.lock();
 
-        if let Some(packet) = send_buffer.back_mut() {
-            if packet.cmd == CMD_DATA {
-                let remain = min(packet.remaining_load(), buf.len());
-                if remain > 0 {
-                    packet.payload_write_slice(&buf[0..remain]);
-                }
+        let mut packets = UcpPacketQueue::new();
+        self.send_ack_list(&mut packets);
+        self.send_packets(packets).await;
+    }
 
-                pos = remain;
-            }
+    // Feed a DATA payload recovered out of band (e.g. by FEC) into reassembly,
+    // the peer learns about it through una, so it is not acked explicitly
+    pub(super) fn inject_data(&self, seq: u32, data: &[u8]) -> bool {
+        let _l = self.lock();
+
+        if self.is_beyond_recv_window(seq) {
+            return false;
         }
 
-        if pos < buf.len() {
-            self.make_packet_send(&buf[pos..]);
+        let mut packet = self.new_noseq_packet(CMD_DATA);
+        packet.seq = seq;
+
+        if !packet.payload_write_slice(data) {
+            return false;
         }
+
+        let key = self.packet_key(packet.session_id);
+        packet.pack(self.config(), key.as_ref());
+        packet.parse(self.config(), |_| key) && self.reassemble(packet)
     }
 
-    fn try_wake_reader(&self) {
+    pub(super) fn out_of_order_bytes(&self) -> usize {
+        let _l = self.lock();
+        let una = self.una.get();
         let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
 
-        if let Some(packet) = recv_queue.front() {
-            let diff = (packet.seq - self.una.get()) as i32;
-            if diff < 0 {
-                if let Some(w) = self.read_waker.take() {
-                    w.wake();
-                }
-            }
-        }
+        recv_queue
+            .iter()
+            .filter(|packet| packet.seq.wrapping_sub(una) as i32 >= 0)
+            .map(|packet| packet.payload as usize)
+            .sum()
     }
 
-    fn try_wake_writer(&self) {
-        if !self.is_send_buffer_overflow() {
-            if let Some(w) = self.write_waker.take() {
-                w.wake();
-            }
+    pub(super) fn estimated_drain_time(&self) -> Option<Duration> {
+        let _l = self.lock();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        let bytes: u64 = send_queue
+            .iter()
+            .chain(send_buffer.iter())
+            .filter(|packet| packet.cmd == CMD_DATA)
+            .map(|packet| packet.payload as u64)
+            .sum();
+
+        match self.delivery_rate.get() {
+            Some(rate) if rate > 0 => Some(Duration::from_micros(bytes * 1_000_000 / rate)),
+            _ => None,
         }
     }
 
-    fn update_metrics(&self) {
-        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
-        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
-        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
-        let una = self.una.get();
-        let rto = self.rto.get();
-        let srtt = self.srtt.get();
-        let rttvar = self.rttvar.get();
-        let rx_seq = if let Some(packet) = recv_queue.front() {
-            packet.seq
-        } else {
-            0
-        };
+    pub(super) fn set_read_timeout(&self, timeout: Option<Duration>) {
+        let _l = self.lock();
+        self.read_timeout.set(timeout);
+        self.read_deadline.set(None);
+    }
 
-        self.metrics
-            .send_queue
-            .store(send_queue.len(), Ordering::Relaxed);
-        self.metrics
-            .recv_queue
-            .store(recv_queue.len(), Ordering::Relaxed);
-        self.metrics
-            .send_buffer
-            .store(send_buffer.len(), Ordering::Relaxed);
-        self.metrics.una.store(una, Ordering::Relaxed);
-        self.metrics.rto.store(rto, Ordering::Relaxed);
-        self.metrics.srtt.store(srtt, Ordering::Relaxed);
-        self.metrics.rttvar.store(rttvar, Ordering::Relaxed);
-        self.metrics.rx_seq.store(rx_seq, Ordering::Relaxed);
+    pub(super) fn set_write_timeout(&self, timeout: Option<Duration>) {
+        let _l = self.lock();
+        self.write_timeout.set(timeout);
+        self.write_deadline.set(None);
     }
 
-    fn is_send_buffer_overflow(&self) -> bool {
-        let remote_window = self.remote_window.get();
-        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
-        send_buffer.len() >= remote_window as usize
+    pub(super) fn read_timeout(&self) -> Option<Duration> {
+        let _l = self.lock();
+        self.read_timeout.get()
     }
 
-    fn check_if_alive(&self) -> bool {
-        let now = Instant::now();
-        let interval = (now - self.alive_time.get()).as_millis();
-        let alive = interval < UCP_STREAM_BROKEN_MILLIS;
+    pub(super) fn write_timeout(&self) -> Option<Duration> {
+        let _l = self.lock();
+        self.write_timeout.get()
+    }
 
-        if !alive {
-            error!(
-                "ucp alive timeout, remote address: {}, session: {}",
-                self.remote_addr,
-                self.session_id.get()
-            );
-        }
+    // The deadline is set when an operation first can't complete and only
+    // its completion clears it, however often it is polled in between, e.g.
+    // by a join woken for another future
+    fn is_io_timed_out(&self, timeout: Option<Duration>, deadline: &Cell<Option<Instant>>) -> bool {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                deadline.set(None);
+                return false;
+            }
+        };
 
-        alive
+        let now = Instant::now();
+        match deadline.get() {
+            Some(deadline_time) if now >= deadline_time => {
+                deadline.set(None);
+                true
+            }
+            Some(_) => false,
+            None => {
+                deadline.set(Some(now + timeout));
+                false
+            }
+        }
     }
 
-    async fn do_heartbeat(&self) {
+    // Nothing else wakes a reader or writer whose deadline passed
+    fn check_io_deadlines(&self) {
         let now = Instant::now();
-        let interval = (now - self.heartbeat.get()).as_millis();
+        let passed = |deadline: Option<Instant>| match deadline {
+            Some(deadline) => now >= deadline,
+            None => false,
+        };
 
-        if interval >= HEARTBEAT_INTERVAL_MILLIS {
-            let mut heartbeat = self.new_noseq_packet(CMD_HEARTBEAT);
-            self.send_packet_directly(&mut heartbeat).await;
-            self.heartbeat.set(now);
+        if passed(self.read_deadline.get()) {
+            if let Some(w) = self.read_waker.take() {
+                w.wake();
+            }
         }
-    }
 
-    async fn send_ack_list(&self) {
-        let ack_list = self.ack_list.take();
-        if ack_list.is_empty() {
+        if passed(self.write_deadline.get()) {
+            if let Some(w) = self.write_waker.take() {
+                w.wake();
+            }
+        }
+    }
+
+    pub(super) fn set_rto(&self, rto: u32) {
+        let _l = self.lock();
+        self.rto_override.set(Some(self.clamp_rto(rto)));
+    }
+
+    pub(super) fn set_cwnd(&self, cwnd: u32) {
+        let _l = self.lock();
+        self.cwnd_override.set(Some(cwnd));
+    }
+
+    pub(super) fn clear_overrides(&self) {
+        let _l = self.lock();
+        self.rto_override.set(None);
+        self.cwnd_override.set(None);
+    }
+
+    pub(super) fn features(&self) -> u32 {
+        let _l = self.lock();
+        self.features.get()
+    }
+
+    pub(super) fn take_error(&self) -> Option<UcpError> {
+        let _l = self.lock();
+        self.error.take()
+    }
+
+    pub(super) fn debug_snapshot(&self) -> String {
+        let _l = self.lock();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        format!(
+            "remote={} session={} state={:?} alive={} error={:?} features={:#x} \
+             seq={} una={} remote_una={} local_window={} remote_window={} \
+             send_window={} cwnd={} srtt={} rttvar={} rto={} rto_override={:?} \
+             send_queue={} send_buffer={} recv_queue={} sent_bytes={} \
+             delivered_bytes={} abandoned={} skipped={} throttled={} truncated={}",
+            self.remote_addr.get(),
+            self.session_id.get(),
+            self.state.get(),
+            self.alive(),
+            self.error.get(),
+            self.features.get(),
+            self.seq.get(),
+            self.una.get(),
+            self.remote_una.get(),
+            self.local_window.get(),
+            self.remote_window.get(),
+            self.send_window(),
+            self.cwnd(),
+            self.srtt.get(),
+            self.rttvar.get(),
+            self.rto.get(),
+            self.rto_override.get(),
+            send_queue.len(),
+            send_buffer.len(),
+            recv_queue.len(),
+            self.metrics.get_sent_bytes(),
+            self.metrics.get_delivered_bytes(),
+            self.metrics.get_abandoned(),
+            self.metrics.get_skipped(),
+            self.metrics.get_throttled(),
+            self.metrics.get_truncated(),
+        )
+    }
+
+    pub(super) fn flow_label(&self) -> Option<u32> {
+        let _l = self.lock();
+        self.flow_label.get()
+    }
+
+    #[cfg(test)]
+    pub(super) fn is_established(&self) -> bool {
+        let _l = self.lock();
+        self.state.get() == UcpState::ESTABLISHED
+    }
+
+    pub(super) fn session_id(&self) -> u32 {
+        let _l = self.lock();
+        self.session_id.get()
+    }
+
+    // Key the peer sealed packet with, for parse
+    pub(super) fn opening_key(&self, packet: &UcpPacket) -> Option<UcpKey> {
+        let _l = self.lock();
+        self.peer_key(packet)
+    }
+
+    pub(super) fn remote_addr(&self) -> SocketAddr {
+        let _l = self.lock();
+        self.remote_addr.get()
+    }
+
+    // The peer shows up from another address, e.g. after a NAT rebinding.
+    // Anyone can copy a session id, so the stream stays on the old address
+    // until the new one answers a heartbeat sent to it
+    pub(super) async fn migrate(&self, remote_addr: SocketAddr) {
+        let _l = self.lock();
+
+        let now = Instant::now();
+        if let Some((addr, _, sent)) = self.migrate_probe.get() {
+            if addr == remote_addr && ((now - sent).as_millis() as u32) < self.rto.get() {
+                return;
+            }
+        }
+
+        info!(
+            "{} probing {}, session: {}",
+            self.remote_addr.get(),
+            remote_addr,
+            self.session_id.get()
+        );
+
+        let mut heartbeat = self.new_noseq_packet(CMD_HEARTBEAT);
+        self.pack(&mut heartbeat);
+        self.migrate_probe
+            .set(Some((remote_addr, heartbeat.timestamp, now)));
+
+        if self
+            .socket
+            .send_to(heartbeat.packed_buffer(), remote_addr)
+            .await
+            .is_ok()
+        {
+            self.capture(heartbeat.packed_buffer());
+        }
+    }
+
+    fn is_migrate_answer(&self, packet: &UcpPacket, remote_addr: SocketAddr) -> bool {
+        let (addr, timestamp) = match self.migrate_probe.get() {
+            Some((addr, timestamp, _)) => (addr, timestamp),
+            None => return false,
+        };
+
+        if packet.cmd != CMD_HEARTBEAT_ACK || packet.payload != 4 || addr != remote_addr {
+            return false;
+        }
+
+        let mut packet = packet.clone();
+        packet.payload_read_u32() == timestamp
+    }
+
+    fn migrated(&self, remote_addr: SocketAddr) {
+        info!(
+            "{} migrated to {}, session: {}",
+            self.remote_addr.get(),
+            remote_addr,
+            self.session_id.get()
+        );
+        self.remote_addr.set(remote_addr);
+        self.migrate_probe.set(None);
+
+        // Whatever was in flight went to the old address, resend it now
+        let fast_resend_times = self.fast_resend_times();
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        for packet in send_queue.iter_mut() {
+            packet.skip_times = fast_resend_times;
+        }
+    }
+
+    pub(super) fn metrics(&self) -> Arc<UcpStreamMetrics> {
+        self.metrics.clone()
+    }
+
+    pub(super) fn config(&self) -> &UcpConfig {
+        unsafe { &*self.config.as_ptr() }
+    }
+
+    pub(super) fn reconfigure(&self, config: UcpConfig) -> std::io::Result<()> {
+        let _l = self.lock();
+        let current = unsafe { &mut *self.config.as_ptr() };
+
+        // Set up once at connect or accept, a new value would be ignored
+        let fixed = [
+            (
+                "skip_checksum",
+                config.skip_checksum != current.skip_checksum,
+            ),
+            ("crypto", !same_arc(&config.crypto, &current.crypto)),
+            ("features", config.features != current.features),
+            ("flow_label", config.flow_label != current.flow_label),
+            (
+                "max_syn_retries",
+                config.max_syn_retries != current.max_syn_retries,
+            ),
+            ("initial_rto", config.initial_rto != current.initial_rto),
+            (
+                "initial_ssthresh",
+                config.initial_ssthresh != current.initial_ssthresh,
+            ),
+            (
+                "max_packet_rate",
+                config.max_packet_rate != current.max_packet_rate,
+            ),
+            ("packet_burst", config.packet_burst != current.packet_burst),
+            (
+                "reset_unknown",
+                config.reset_unknown != current.reset_unknown,
+            ),
+            (
+                "address_filter",
+                !same_arc(&config.address_filter, &current.address_filter),
+            ),
+            (
+                "congestion_control",
+                !same_arc(&config.congestion_control, &current.congestion_control),
+            ),
+            (
+                "socket_factory",
+                !same_arc(&config.socket_factory, &current.socket_factory),
+            ),
+        ];
+
+        if let Some((name, _)) = fixed.iter().find(|(_, changed)| *changed) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} can't change on a live stream", name),
+            ));
+        }
+
+        // Queued packets are already sized for the current MTU
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+        if config.mtu != current.mtu && !(send_queue.is_empty() && send_buffer.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "mtu can't change with packets in flight",
+            ));
+        }
+
+        self.local_window
+            .set(config.recv_window.unwrap_or(DEFAULT_WINDOW));
+
+        current.max_in_flight = config.max_in_flight;
+        current.rekey_packets = config.rekey_packets;
+        current.max_send_buffer = config.max_send_buffer;
+        current.min_send_size = config.min_send_size;
+        current.recv_window = config.recv_window;
+        current.send_window = config.send_window;
+        current.mtu = config.mtu;
+        current.fast_resend_times = config.fast_resend_times;
+        current.min_rto = config.min_rto;
+        current.max_rto = config.max_rto;
+        current.max_packet_age = config.max_packet_age;
+        current.recv_deadline = config.recv_deadline;
+        current.on_sample = config.on_sample;
+        current.on_acked = config.on_acked;
+        current.heartbeat_interval = config.heartbeat_interval;
+        current.idle_timeout = config.idle_timeout;
+        current.stall_timeout = config.stall_timeout;
+        current.slow_reader_timeout = config.slow_reader_timeout;
+        current.on_close = config.on_close;
+        current.on_timeout = config.on_timeout;
+        #[cfg(feature = "pcap")]
+        {
+            current.capture = config.capture;
+        }
+        Ok(())
+    }
+
+    pub(super) fn packet_truncated(&self, remote_addr: SocketAddr) {
+        error!("recv oversized packet from {}", remote_addr);
+        self.metrics.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn die(&self) {
+        self.alive.store(false, Ordering::Relaxed);
+
+        if let Some(w) = self.read_waker.take() {
+            w.wake()
+        }
+
+        if let Some(w) = self.write_waker.take() {
+            w.wake()
+        }
+    }
+
+    fn dead_error(&self) -> Error {
+        match self.error.get() {
+            Some(e) => Error::from(e),
+            None => Error::from(ErrorKind::Other),
+        }
+    }
+
+    fn lock(&self) -> Lock<'_> {
+        let backoff = Backoff::new();
+        while self.lock.compare_and_swap(0, 1, Ordering::Acquire) != 0 {
+            backoff.snooze();
+        }
+        Lock { inner: self }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(0, Ordering::SeqCst);
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> usize {
+        let mut size = 0;
+        let una = self.una.get();
+        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
+
+        while size < buf.len() && !recv_queue.is_empty() {
+            if let Some(packet) = recv_queue.front_mut() {
+                let diff = packet.seq.wrapping_sub(una) as i32;
+                if diff >= 0 {
+                    break;
+                }
+
+                size += packet.payload_read_slice(&mut buf[size..]);
+            }
+
+            let no_remain_payload = recv_queue
+                .front()
+                .map(|packet| packet.payload_remaining() == 0)
+                .unwrap();
+
+            if no_remain_payload {
+                recv_queue.pop_front();
+            }
+        }
+
+        if size > 0 {
+            self.read_time.set(Instant::now());
+        }
+
+        size
+    }
+
+    fn send(&self, buf: &[u8]) -> usize {
+        let mut pos = 0;
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+
+        if let Some(packet) = send_buffer.back_mut() {
+            if packet.cmd == CMD_DATA {
+                let remain = min(packet.remaining_load(), buf.len());
+                if remain > 0 {
+                    packet.payload_write_slice(&buf[0..remain]);
+                }
+
+                pos = remain;
+            }
+        }
+
+        if pos < buf.len() {
+            pos += self.make_packet_send(&buf[pos..]);
+        }
+
+        pos
+    }
+
+    fn try_wake_reader(&self) {
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+
+        if let Some(packet) = recv_queue.front() {
+            let diff = packet.seq.wrapping_sub(self.una.get()) as i32;
+            if diff < 0 {
+                if let Some(w) = self.read_waker.take() {
+                    w.wake();
+                }
+            }
+        }
+    }
+
+    fn try_wake_writer(&self) {
+        if !self.is_send_buffer_overflow() {
+            if let Some(w) = self.write_waker.take() {
+                w.wake();
+            }
+        }
+    }
+
+    // Bytes per second acked by the peer, smoothed over sample intervals
+    fn update_delivery_rate(&self) {
+        let now = Instant::now();
+        let (start, delivered) = self.delivery_sample.get();
+        let elapsed = (now - start).as_millis();
+
+        if elapsed < DELIVERY_RATE_INTERVAL_MILLIS {
+            return;
+        }
+
+        let bytes = self.delivered.get() - delivered;
+        self.delivery_sample.set((now, self.delivered.get()));
+
+        // Idle intervals say nothing about the path
+        if bytes == 0 {
+            return;
+        }
+
+        let rate = (bytes as u128 * 1000 / elapsed) as u64;
+        self.delivery_rate.set(Some(match self.delivery_rate.get() {
+            Some(old) => (old * 7 + rate) / 8,
+            None => rate,
+        }));
+    }
+
+    fn update_metrics(&self) {
+        self.update_delivery_rate();
+
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+        let una = self.una.get();
+        let rto = self.rto.get();
+        let srtt = self.srtt.get();
+        let rttvar = self.rttvar.get();
+        let send_delay = self.send_delay.get().unwrap_or(0);
+        let recv_delay = self.recv_delay.get().unwrap_or(0);
+        let rx_seq = if let Some(packet) = recv_queue.front() {
+            packet.seq
+        } else {
+            0
+        };
+
+        self.metrics
+            .send_queue
+            .store(send_queue.len(), Ordering::Relaxed);
+        self.metrics
+            .recv_queue
+            .store(recv_queue.len(), Ordering::Relaxed);
+        self.metrics
+            .send_buffer
+            .store(send_buffer.len(), Ordering::Relaxed);
+        self.metrics.una.store(una, Ordering::Relaxed);
+        self.metrics.rto.store(rto, Ordering::Relaxed);
+        self.metrics.srtt.store(srtt, Ordering::Relaxed);
+        self.metrics.rttvar.store(rttvar, Ordering::Relaxed);
+        if let Some(min_rtt) = self.min_rtt.get() {
+            self.metrics.min_rtt.store(min_rtt, Ordering::Relaxed);
+            self.metrics
+                .rtt_inflation
+                .store(srtt * 100 / min_rtt.max(1), Ordering::Relaxed);
+        }
+        let congestion = unsafe { &*self.congestion.as_ptr() };
+        self.metrics
+            .cwnd
+            .store(congestion.cwnd(), Ordering::Relaxed);
+        self.metrics
+            .path_mtu
+            .store(self.path_mtu.get(), Ordering::Relaxed);
+        self.metrics.rx_seq.store(rx_seq, Ordering::Relaxed);
+        self.metrics.send_delay.store(send_delay, Ordering::Relaxed);
+        self.metrics.recv_delay.store(recv_delay, Ordering::Relaxed);
+
+        if let Some(ref on_sample) = self.config().on_sample {
+            on_sample(UcpSample {
+                srtt: srtt,
+                rttvar: rttvar,
+                rto: self.rto_override.get().unwrap_or(rto),
+                in_flight: send_queue.len(),
+                window: self.send_window(),
+            });
+        }
+    }
+
+    fn is_send_buffer_overflow(&self) -> bool {
+        let remote_window = self.remote_window.get() as usize;
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+        send_buffer.len() >= self.config().max_send_buffer.unwrap_or(remote_window)
+    }
+
+    fn check_if_alive(&self) -> bool {
+        let now = Instant::now();
+        let interval = (now - self.alive_time.get()).as_millis();
+        let idle_timeout = self
+            .config()
+            .idle_timeout
+            .map_or(UCP_STREAM_BROKEN_MILLIS, |timeout| timeout as u128);
+        let alive = interval < idle_timeout;
+
+        if !alive {
+            error!(
+                "ucp alive timeout, remote address: {}, session: {}",
+                self.remote_addr.get(),
+                self.session_id.get()
+            );
+        }
+
+        alive
+    }
+
+    fn check_if_handshake_failed(&self) -> bool {
+        let max_syn_retries = match self.config().max_syn_retries {
+            Some(max_syn_retries) => max_syn_retries,
+            None => return false,
+        };
+
+        // Give the last resend a full backoff RTO to be answered
+        let now = self.timestamp();
+        let rto = self.rto.get();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let failed = send_queue.iter().any(|packet| {
+            (packet.cmd == CMD_SYN || packet.cmd == CMD_SYN_ACK)
+                && packet.xmit >= max_syn_retries
+                && now.wrapping_sub(packet.timestamp)
+                    >= rto.saturating_mul(1 << min(packet.xmit, RTO_BACKOFF_LIMIT))
+        });
+
+        if failed {
+            error!(
+                "ucp handshake timeout, remote address: {}, session: {}",
+                self.remote_addr.get(),
+                self.session_id.get()
+            );
+        }
+
+        failed
+    }
+
+    fn check_if_slow_reader(&self) -> bool {
+        let timeout = match self.config().slow_reader_timeout {
+            Some(timeout) => timeout as u128,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let una = self.una.get();
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let unread = match recv_queue.front() {
+            Some(packet) => (packet.seq.wrapping_sub(una) as i32) < 0,
+            None => false,
+        };
+
+        if !unread {
+            self.read_time.set(now);
+            return false;
+        }
+
+        let slow = (now - self.read_time.get()).as_millis() >= timeout;
+        if slow {
+            error!(
+                "ucp reader stopped reading, remote address: {}, session: {}",
+                self.remote_addr.get(),
+                self.session_id.get()
+            );
+        }
+
+        slow
+    }
+
+    fn send_fin(&self) {
+        let fin = self.new_packet(CMD_FIN);
+        self.enqueue_packet(fin);
+        self.fin_sent.set(true);
+    }
+
+    // Each side closes its half with a FIN on shutdown, queued behind the
+    // data written before it. The stream is closed when both FINs are in
+    // and ours is acked
+    async fn check_if_closed(&self) {
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        if self.state.get() != UcpState::CLOSING
+            || !send_queue.is_empty()
+            || !send_buffer.is_empty()
+        {
+            return;
+        }
+
+        if self.fin_received.get() {
+            // Tells the peer its FIN is acked without waiting for the ack
+            // list, no output tick follows once the stream is closed
+            let mut fin_ack = self.new_noseq_packet(CMD_FIN_ACK);
+            self.send_packet_directly(&mut fin_ack).await;
+            self.closed("FIN acked");
+        }
+    }
+
+    fn closed(&self, trigger: &str) {
+        self.set_state(UcpState::CLOSED, trigger);
+        info!(
+            "{} closed, session: {}",
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+        self.die();
+
+        if let Some(ref on_close) = self.config().on_close {
+            on_close(self.remote_addr.get());
+        }
+    }
+
+    fn check_if_stalled(&self) -> bool {
+        let now = Instant::now();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+
+        if send_queue.is_empty() {
+            self.progress_time.set(now);
+            self.stalled_acks.set(0);
+            return false;
+        }
+
+        let interval = (now - self.progress_time.get()).as_millis();
+        let stall_timeout = self
+            .config()
+            .stall_timeout
+            .map_or(UCP_STREAM_STALLED_MILLIS, |timeout| timeout as u128);
+        let stalled = interval >= stall_timeout && self.stalled_acks.get() > 0;
+
+        if stalled {
+            error!(
+                "ucp peer stalled, remote address: {}, session: {}, una: {}",
+                self.remote_addr.get(),
+                self.session_id.get(),
+                self.remote_una.get()
+            );
+        }
+
+        stalled
+    }
+
+    fn check_recv_deadline(&self) {
+        let deadline = match self.config().recv_deadline {
+            Some(deadline) => deadline as u128,
+            None => return,
+        };
+
+        let una = self.una.get();
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let next = recv_queue
+            .iter()
+            .map(|packet| packet.seq)
+            .find(|seq| seq.wrapping_sub(una) as i32 > 0);
+
+        let next = match (next, self.gap_time.get()) {
+            (None, _) => {
+                self.gap_time.set(None);
+                return;
+            }
+            (Some(_), None) => {
+                self.gap_time.set(Some(Instant::now()));
+                return;
+            }
+            (Some(next), Some(gap_time)) => {
+                if gap_time.elapsed().as_millis() < deadline {
+                    return;
+                }
+                next
+            }
+        };
+
+        warn!(
+            "skip {} missing packets from {}, session: {}",
+            next.wrapping_sub(una),
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+        self.metrics
+            .skipped
+            .fetch_add(next.wrapping_sub(una) as usize, Ordering::Relaxed);
+
+        let mut una = next;
+        for packet in recv_queue.iter() {
+            if packet.seq == una {
+                if packet.cmd == CMD_FIN {
+                    self.fin_received.set(true);
+                }
+                una = una.wrapping_add(1);
+            }
+        }
+
+        self.advance_una(una);
+        if let Some(gap_time) = self.gap_time.take() {
+            self.reorder_delayed(gap_time);
+        }
+
+        self.try_wake_reader();
+    }
+
+    // Called when una moved past the packets waiting behind a gap, the
+    // first of them waited longest
+    fn reorder_delayed(&self, gap_time: Instant) {
+        let delay = gap_time.elapsed().as_millis() as u32;
+        let smoothed = match self.metrics.reorder_delay.load(Ordering::Relaxed) {
+            0 => delay,
+            old => (old * 7 + delay) / 8,
+        };
+
+        self.metrics
+            .reorder_delay
+            .store(smoothed, Ordering::Relaxed);
+        self.metrics
+            .max_reorder_delay
+            .fetch_max(delay, Ordering::Relaxed);
+    }
+
+    async fn transmit(&self) {
+        let mut packets = UcpPacketQueue::new();
+        self.do_heartbeat(&mut packets);
+        self.send_ack_list(&mut packets);
+        if self.timeout_resend(&mut packets) {
+            self.send_pending_packets(&mut packets);
+        }
+        self.send_packets(packets).await;
+
+        if self.state.get() == UcpState::ESTABLISHED {
+            self.probe_path_mtu().await;
+        }
+    }
+
+    // Binary search between the largest size acked and the smallest size
+    // consistently lost, one probe outstanding at a time
+    async fn probe_path_mtu(&self) {
+        if self.features.get() & UCP_FEATURE_MTU_PROBE == 0 {
+            return;
+        }
+
+        let now = self.timestamp();
+        if let Some((size, timestamp)) = self.mtu_probe.get() {
+            if now.wrapping_sub(timestamp) < self.rto.get().saturating_mul(2) {
+                return;
+            }
+
+            self.mtu_probe.set(None);
+            let losses = self.mtu_probe_losses.get() + 1;
+            if losses >= MTU_PROBE_LOSSES {
+                self.mtu_probe_ceiling.set(size - 1);
+                self.mtu_probe_losses.set(0);
+            } else {
+                self.mtu_probe_losses.set(losses);
+            }
+        }
+
+        let path_mtu = self.path_mtu.get();
+        let ceiling = match self.config().mtu {
+            Some(mtu) => min(self.mtu_probe_ceiling.get(), mtu),
+            None => self.mtu_probe_ceiling.get(),
+        };
+        if ceiling < path_mtu + MTU_PROBE_STEP {
+            return;
+        }
+
+        if !self.acquire_send() {
+            return;
+        }
+
+        let size = path_mtu + (ceiling - path_mtu) / 2;
+        let overhead = seal_overhead(self.config());
+
+        let mut probe = self.new_noseq_packet(CMD_MTU_PROBE);
+        probe.limit = UCP_PACKET_SIZE;
+        probe.payload_write_u32(size as u32);
+        let padding = size - UCP_PACKET_META_SIZE - overhead - 4;
+        probe.payload_write_slice(&vec![0u8; padding]);
+
+        self.mtu_probe.set(Some((size, now)));
+        self.send_packet_directly(&mut probe).await;
+    }
+
+    async fn process_mtu_probe(&self, mut packet: Box<UcpPacket>) {
+        let mut probe_ack = self.new_noseq_packet(CMD_MTU_PROBE_ACK);
+        probe_ack.payload_write_u32(packet.payload_read_u32());
+        self.send_packet_directly(&mut probe_ack).await;
+    }
+
+    fn process_mtu_probe_ack(&self, mut packet: Box<UcpPacket>) {
+        let size = packet.payload_read_u32() as usize;
+        if let Some((probe_size, _)) = self.mtu_probe.get() {
+            if probe_size == size {
+                self.mtu_probe.set(None);
+                self.mtu_probe_losses.set(0);
+                self.path_mtu.set(size);
+                info!(
+                    "{} path mtu: {}, session: {}",
+                    self.remote_addr.get(),
+                    size,
+                    self.session_id.get()
+                );
+            }
+        }
+    }
+
+    // Without probing agreed on, trust the full packet size as before
+    fn set_features(&self, features: u32) {
+        self.features.set(features);
+        if features & UCP_FEATURE_MTU_PROBE == 0 {
+            self.path_mtu.set(UCP_PACKET_SIZE);
+        }
+    }
+
+    fn do_heartbeat(&self, packets: &mut UcpPacketQueue) {
+        let now = Instant::now();
+        let interval = (now - self.heartbeat.get()).as_millis();
+
+        let heartbeat_interval = self
+            .config()
+            .heartbeat_interval
+            .map_or(HEARTBEAT_INTERVAL_MILLIS, |interval| interval as u128);
+
+        // Nothing tells us when a zero window reopens, probe it every RTO
+        // so the peer's answer carries its current window
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+        let heartbeat_interval = if self.send_window() == 0 && !send_buffer.is_empty() {
+            min(heartbeat_interval, self.rto.get() as u128)
+        } else {
+            heartbeat_interval
+        };
+
+        if interval >= heartbeat_interval {
+            packets.push_back(self.new_noseq_packet(CMD_HEARTBEAT));
+            self.heartbeat.set(now);
+        }
+    }
+
+    fn send_ack_list(&self, packets: &mut UcpPacketQueue) {
+        let ack_list = self.ack_list.take();
+        if ack_list.is_empty() {
             return;
         }
 
@@ -418,7 +1626,7 @@ impl InnerStream {
 
         for &(seq, timestamp) in ack_list.iter() {
             if packet.remaining_load() < 8 {
-                self.send_packet_directly(&mut packet).await;
+                packets.push_back(packet);
                 packet = self.new_noseq_packet(CMD_ACK);
             }
 
@@ -426,105 +1634,314 @@ impl InnerStream {
             packet.payload_write_u32(timestamp);
         }
 
-        self.send_packet_directly(&mut packet).await;
+        packets.push_back(packet);
     }
 
-    async fn timeout_resend(&self) {
+    fn timeout_resend(&self, packets: &mut UcpPacketQueue) -> bool {
         let now = self.timestamp();
         let una = self.una.get();
-        let rto = self.rto.get();
-        let mut resend = Vec::new();
-
-        {
-            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let window = self.recv_window();
+        let rto = self.rto_override.get().unwrap_or(self.rto.get());
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let mut timeout = false;
+        let mut skipped = false;
+        let mut drained = true;
+
+        for packet in send_queue.iter_mut() {
+            let interval = now.wrapping_sub(packet.timestamp);
+            let backoff_rto = rto.saturating_mul(1 << min(packet.xmit, RTO_BACKOFF_LIMIT));
+            let skip_resend = packet.skip_times >= self.fast_resend_times();
+
+            if interval >= backoff_rto || skip_resend {
+                if !self.acquire_send() {
+                    drained = false;
+                    break;
+                }
 
-            for packet in send_queue.iter_mut() {
-                let interval = now - packet.timestamp;
-                let skip_resend = packet.skip_times >= SKIP_RESEND_TIMES;
+                if self.is_packet_expired(packet, now) {
+                    self.abandon_packet(packet);
+                }
 
-                if interval >= rto || skip_resend {
-                    packet.skip_times = 0;
-                    packet.window = self.local_window.get();
-                    packet.una = una;
-                    packet.timestamp = now;
-                    packet.xmit += 1;
+                packet.skip_times = 0;
+                packet.window = window;
+                packet.una = una;
+                packet.timestamp = now;
+                packet.xmit += 1;
+                self.metrics.resent_packets.fetch_add(1, Ordering::Relaxed);
 
-                    resend.push(packet.clone());
+                if interval >= backoff_rto {
+                    timeout = true;
+                } else {
+                    skipped = true;
                 }
+
+                packets.push_back(packet.clone());
             }
         }
 
-        for packet in resend.iter_mut() {
-            self.send_packet_directly(packet).await;
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        if timeout {
+            congestion.on_loss(true);
+        }
+        if skipped {
+            congestion.on_loss(false);
+        }
+
+        drained
+    }
+
+    fn fast_resend_times(&self) -> u32 {
+        self.config().fast_resend_times.unwrap_or(SKIP_RESEND_TIMES)
+    }
+
+    fn is_packet_expired(&self, packet: &UcpPacket, now: u32) -> bool {
+        match self.config().max_packet_age {
+            Some(age) => {
+                packet.cmd == CMD_DATA
+                    && packet.payload > 0
+                    && now.wrapping_sub(packet.first_timestamp) >= age
+            }
+            None => false,
         }
     }
 
-    async fn send_pending_packets(&self) {
+    fn abandon_packet(&self, packet: &mut UcpPacket) {
+        // Keep resending the seq without payload, so the receiver can move
+        // una past the gap instead of waiting for stale data
+        warn!(
+            "abandon packet {} to {}, session: {}",
+            packet.seq,
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+        packet.payload = 0;
+        self.metrics.abandoned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn send_pending_packets(&self, packets: &mut UcpPacketQueue) {
         let now = self.timestamp();
         let una = self.una.get();
-        let window = self.remote_window.get() as usize;
-        let mut pending = Vec::new();
+        let window = self.recv_window();
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
 
-        {
-            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
-            let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
-
-            while send_queue.len() < window {
-                if let Some(q) = send_queue.front() {
-                    if let Some(p) = send_buffer.front() {
-                        let seq_diff = (p.seq - q.seq) as usize;
-                        if seq_diff >= window {
-                            break;
-                        }
-                    }
+        while self.can_send_pending(send_queue, send_buffer) {
+            if let Some(mut packet) = send_buffer.pop_front() {
+                packet.window = window;
+                packet.una = una;
+                packet.timestamp = now;
+                packet.first_timestamp = now;
+
+                if let Some(q) = send_queue.back() {
+                    debug_assert!(packet.seq.wrapping_sub(q.seq) as i32 > 0);
                 }
 
-                if let Some(mut packet) = send_buffer.pop_front() {
-                    packet.window = self.local_window.get();
-                    packet.una = una;
-                    packet.timestamp = now;
+                packets.push_back(packet.clone());
+                send_queue.push_back(packet);
+            }
+        }
+
+        if send_buffer.is_empty() {
+            self.flushing.set(false);
+        }
+    }
 
-                    pending.push(packet.clone());
-                    send_queue.push_back(packet);
-                } else {
-                    break;
-                }
+    fn can_send_pending(&self, send_queue: &UcpPacketQueue, send_buffer: &UcpPacketQueue) -> bool {
+        if send_buffer.is_empty() || self.pending_limit(send_queue, send_buffer).is_some() {
+            return false;
+        }
+
+        // Only the last packet is still being written to
+        if send_buffer.len() == 1 && send_buffer.iter().any(|p| self.is_tinygram(p)) {
+            return false;
+        }
+
+        self.acquire_send()
+    }
+
+    fn pending_limit(
+        &self,
+        send_queue: &UcpPacketQueue,
+        send_buffer: &UcpPacketQueue,
+    ) -> Option<UcpSendLimit> {
+        let in_flight = send_queue.len();
+        let span = match (send_queue.front(), send_buffer.front()) {
+            (Some(q), Some(p)) => p.seq.wrapping_sub(q.seq) as usize + 1,
+            _ => in_flight + 1,
+        };
+
+        if let Some(max_in_flight) = self.config().max_in_flight {
+            if in_flight >= max_in_flight {
+                return Some(UcpSendLimit::InFlight);
             }
         }
 
-        for packet in pending.iter_mut() {
-            self.send_packet_directly(packet).await;
+        if in_flight >= self.send_window() as usize || span > self.send_window() as usize {
+            Some(UcpSendLimit::Window)
+        } else if in_flight >= self.cwnd() as usize || span > self.cwnd() as usize {
+            Some(UcpSendLimit::Congestion)
+        } else {
+            None
+        }
+    }
+
+    pub(super) fn send_limit(&self) -> Option<UcpSendLimit> {
+        let _l = self.lock();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        if self.is_send_buffer_overflow() {
+            Some(UcpSendLimit::SendBuffer)
+        } else if send_buffer.is_empty() {
+            None
+        } else {
+            self.pending_limit(send_queue, send_buffer)
+        }
+    }
+
+    fn is_tinygram(&self, packet: &UcpPacket) -> bool {
+        let min_send_size = match self.config().min_send_size {
+            Some(min_send_size) => min_send_size,
+            None => return false,
+        };
+
+        packet.cmd == CMD_DATA
+            && (packet.payload as usize) < min_send_size
+            && !self.flushing.get()
+            && self.timestamp().wrapping_sub(packet.timestamp) < MIN_SEND_DELAY_MILLIS
+    }
+
+    // Packets recv_queue can still take, the ones received in order but
+    // not read yet take up local_window
+    fn recv_window(&self) -> u32 {
+        let una = self.una.get();
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let unread = recv_queue
+            .iter()
+            .take_while(|packet| (packet.seq.wrapping_sub(una) as i32) < 0)
+            .count();
+
+        self.local_window.get().saturating_sub(unread as u32)
+    }
+
+    fn send_window(&self) -> u32 {
+        let window = self.remote_window.get();
+        min(window, self.config().send_window.unwrap_or(window))
+    }
+
+    // The override only ever lowers what congestion control allows, the
+    // peer's window still holds
+    fn cwnd(&self) -> u32 {
+        let congestion = unsafe { &*self.congestion.as_ptr() };
+        match self.cwnd_override.get() {
+            Some(cwnd) => min(cwnd, congestion.cwnd()),
+            None => congestion.cwnd(),
         }
+    }
+
+    fn packet_limit(&self) -> usize {
+        let config = self.config();
+        let limit = config.mtu.map_or(UCP_PACKET_SIZE, |mtu| {
+            mtu.clamp(MIN_PACKET_SIZE, UCP_PACKET_SIZE)
+        });
+        let limit = min(limit, self.path_mtu.get());
 
-        self.try_wake_writer();
+        // Leave room for what sealing the payload adds
+        limit - seal_overhead(config)
+    }
+
+    fn set_state(&self, state: UcpState, trigger: &str) {
+        debug!(
+            "{} session: {}, state: {:?} -> {:?} on {}",
+            self.remote_addr.get(),
+            self.session_id.get(),
+            self.state.get(),
+            state,
+            trigger
+        );
+        self.state.set(state);
     }
 
     pub(super) fn connecting(&self) {
-        self.state.set(UcpState::CONNECTING);
         self.session_id.set(random::<u32>());
+        self.set_state(UcpState::CONNECTING, "connect");
 
-        let syn = self.new_packet(CMD_SYN);
-        self.send_packet(syn);
+        let mut syn = self.new_packet(CMD_SYN);
+        syn.payload_write_u32(self.config().features);
+        if let Some(flow_label) = self.config().flow_label {
+            syn.payload_write_u32(flow_label);
+            self.flow_label.set(Some(flow_label));
+        }
+        self.enqueue_packet(syn);
         info!(
             "connecting ucp server {}, session: {}",
-            self.remote_addr,
+            self.remote_addr.get(),
             self.session_id.get()
         );
     }
 
-    fn accepting(&self, packet: Box<UcpPacket>) {
-        self.state.set(UcpState::ACCEPTING);
+    fn accepting(&self, mut packet: Box<UcpPacket>) {
         self.session_id.set(packet.session_id);
-        self.una.set(packet.seq + 1);
+        self.set_state(UcpState::ACCEPTING, "SYN");
+        self.una.set(packet.seq.wrapping_add(1));
         self.remote_window.set(packet.window);
 
         let mut syn_ack = self.new_packet(CMD_SYN_ACK);
-        syn_ack.payload_write_u32(packet.seq);
-        syn_ack.payload_write_u32(packet.timestamp);
-        self.send_packet(syn_ack);
+        self.write_syn_ack(&mut syn_ack, &mut packet);
+        self.enqueue_packet(syn_ack);
         info!(
             "accepting ucp client {}, session: {}",
-            self.remote_addr,
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+    }
+
+    fn write_syn_ack(&self, syn_ack: &mut UcpPacket, syn: &mut UcpPacket) {
+        syn_ack.payload_write_u32(syn.seq);
+        syn_ack.payload_write_u32(syn.timestamp);
+
+        // Peers which offer no features expect the plain 8 bytes SYN_ACK
+        if syn.payload >= 4 {
+            let features = syn.payload_read_u32() & self.config().features;
+            self.set_features(features);
+            syn_ack.payload_write_u32(features);
+        }
+
+        if syn.payload >= 8 {
+            self.flow_label.set(Some(syn.payload_read_u32()));
+        }
+    }
+
+    // Both sides sent SYN, the lower session id keeps connecting and the
+    // other side turns its own SYN into the SYN_ACK for the peer's session
+    fn simultaneous_open(&self, mut packet: Box<UcpPacket>) {
+        if self.session_id.get() < packet.session_id {
+            return;
+        }
+
+        self.session_id.set(packet.session_id);
+        self.set_state(UcpState::ACCEPTING, "simultaneous SYN");
+        self.una.set(packet.seq.wrapping_add(1));
+        self.remote_window.set(packet.window);
+        self.alive_time.set(Instant::now());
+
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+
+        for syn in send_queue.iter_mut().chain(send_buffer.iter_mut()) {
+            syn.session_id = packet.session_id;
+
+            if syn.cmd == CMD_SYN {
+                syn.cmd = CMD_SYN_ACK;
+                syn.payload = 0;
+                syn.skip_times = self.fast_resend_times();
+                self.write_syn_ack(syn, &mut packet);
+            }
+        }
+
+        info!(
+            "simultaneous open with {}, session: {}",
+            self.remote_addr.get(),
             self.session_id.get()
         );
     }
@@ -539,20 +1956,38 @@ impl InnerStream {
             return;
         }
 
+        if packet.cmd == CMD_RST {
+            self.process_rst(packet);
+            return;
+        }
+
         self.alive_time.set(Instant::now());
+        self.heartbeat.set(Instant::now());
         self.remote_window.set(packet.window);
 
         let state = self.state.get();
         match state {
+            // The final ACK was lost or coalesced into the first DATA, but
+            // anything sent once the peer is established acks the SYN_ACK
+            UcpState::ACCEPTING if !packet.is_syn() && self.is_syn_ack_acked(packet.una) => {
+                self.set_state(UcpState::ESTABLISHED, "una");
+                info!(
+                    "{} established, session: {}",
+                    self.remote_addr.get(),
+                    self.session_id.get()
+                );
+                self.process_state_established(packet).await;
+            }
             UcpState::ACCEPTING => {
                 self.process_state_accepting(packet);
             }
             UcpState::CONNECTING => {
                 self.process_state_connecting(packet).await;
             }
-            UcpState::ESTABLISHED => {
+            UcpState::ESTABLISHED | UcpState::CLOSING => {
                 self.process_state_established(packet).await;
             }
+            UcpState::CLOSED => {}
             UcpState::NONE => {
                 error!("unexpect UcpState::NONE");
             }
@@ -560,15 +1995,17 @@ impl InnerStream {
     }
 
     fn process_state_accepting(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_ACK && packet.payload == 8 {
+        if packet.is_syn() {
+            self.resend_syn_ack();
+        } else if packet.cmd == CMD_ACK && packet.payload == 8 {
             let seq = packet.payload_read_u32();
             let timestamp = packet.payload_read_u32();
 
             if self.process_an_ack(seq, timestamp) {
-                self.state.set(UcpState::ESTABLISHED);
+                self.set_state(UcpState::ESTABLISHED, "ACK");
                 info!(
                     "{} established, session: {}",
-                    self.remote_addr,
+                    self.remote_addr.get(),
                     self.session_id.get()
                 );
             }
@@ -580,6 +2017,32 @@ impl InnerStream {
         }
     }
 
+    fn is_syn_ack_acked(&self, una: u32) -> bool {
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        send_queue
+            .iter()
+            .find(|packet| packet.cmd == CMD_SYN_ACK)
+            .map(|packet| una.wrapping_sub(packet.seq) as i32 > 0)
+            .unwrap_or(false)
+    }
+
+    fn resend_syn_ack(&self) {
+        info!(
+            "duplicate syn from {}, session: {}",
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+
+        // The SYN_ACK is still unacked, either waiting in send_buffer to be
+        // sent or in send_queue where it will be resent on the next output
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        for packet in send_queue.iter_mut() {
+            if packet.cmd == CMD_SYN_ACK {
+                packet.skip_times = self.fast_resend_times();
+            }
+        }
+    }
+
     async fn process_state_connecting(&self, packet: Box<UcpPacket>) {
         self.process_syn_ack(packet).await;
     }
@@ -598,10 +2061,24 @@ impl InnerStream {
                 self.process_syn_ack(packet).await;
             }
             CMD_HEARTBEAT => {
-                self.process_heartbeat().await;
+                self.process_heartbeat(packet).await;
             }
             CMD_HEARTBEAT_ACK => {
-                self.process_heartbeat_ack();
+                self.process_heartbeat_ack(packet);
+            }
+            CMD_MTU_PROBE => {
+                self.process_mtu_probe(packet).await;
+            }
+            CMD_MTU_PROBE_ACK => {
+                self.process_mtu_probe_ack(packet);
+            }
+            CMD_FIN | CMD_REKEY => {
+                self.process_data(packet);
+            }
+            CMD_FIN_ACK => {
+                if self.state.get() == UcpState::CLOSING && self.fin_sent.get() {
+                    self.closed("FIN_ACK");
+                }
             }
             _ => {
                 error!("unexpect packet.cmd: {}", packet.cmd);
@@ -609,94 +2086,239 @@ impl InnerStream {
         }
     }
 
+    fn advance_una(&self, una: u32) {
+        debug_assert!(una.wrapping_sub(self.una.get()) as i32 > 0);
+        self.una.set(una);
+    }
+
     fn process_una(&self, una: u32) {
+        if self.remote_una.get() != una {
+            self.remote_una.set(una);
+            self.progress_time.set(Instant::now());
+            self.stalled_acks.set(0);
+        }
+
         let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let mut acked: Option<(u32, u32)> = None;
 
         while !send_queue.is_empty() {
             let diff = send_queue
                 .front()
-                .map(|packet| (packet.seq - una) as i32)
+                .map(|packet| packet.seq.wrapping_sub(una) as i32)
                 .unwrap();
 
             if diff < 0 {
-                send_queue.pop_front();
+                if let Some(packet) = send_queue.pop_front() {
+                    self.packet_delivered(&packet);
+                    self.bytes_delivered(&packet);
+
+                    acked = match acked {
+                        Some((first, last)) if packet.seq == last.wrapping_add(1) => {
+                            Some((first, packet.seq))
+                        }
+                        Some((first, last)) => {
+                            self.packets_acked(first, last);
+                            Some((packet.seq, packet.seq))
+                        }
+                        None => Some((packet.seq, packet.seq)),
+                    };
+                }
             } else {
                 break;
             }
         }
+
+        if let Some((first, last)) = acked {
+            self.packets_acked(first, last);
+        }
+
+        // Packets acked ahead of una are delivered in order only now
+        let sacked = unsafe { &mut *self.sacked.as_ptr() };
+        sacked.retain(|&(seq, payload)| {
+            if (seq.wrapping_sub(una) as i32) < 0 {
+                self.metrics
+                    .delivered_bytes
+                    .fetch_add(payload as u64, Ordering::Relaxed);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn packets_acked(&self, first: u32, last: u32) {
+        if let Some(ref on_acked) = self.config().on_acked {
+            on_acked(first..=last);
+        }
     }
 
     fn process_ack(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_ACK && packet.payload % 8 == 0 {
-            while packet.payload_remaining() > 0 {
-                let seq = packet.payload_read_u32();
-                let timestamp = packet.payload_read_u32();
-                self.process_an_ack(seq, timestamp);
+        self.stalled_acks
+            .set(self.stalled_acks.get().saturating_add(1));
+
+        while packet.payload_remaining() > 0 {
+            let seq = packet.payload_read_u32();
+            let timestamp = packet.payload_read_u32();
+            if !self.process_an_ack(seq, timestamp) {
+                self.metrics.dup_acks.fetch_add(1, Ordering::Relaxed);
             }
+            self.update_one_way_delay(timestamp, packet.timestamp);
         }
     }
 
     fn process_data(&self, packet: Box<UcpPacket>) {
+        // Leave it unacked and let the sender resend it once the reader
+        // made room
+        if self.is_beyond_recv_window(packet.seq) {
+            return;
+        }
+
+        self.metrics
+            .recv_bytes
+            .fetch_add(packet.payload as u64, Ordering::Relaxed);
+
         let ack_list = unsafe { &mut *self.ack_list.as_ptr() };
         ack_list.push((packet.seq, packet.timestamp));
+
+        self.reassemble(packet);
+    }
+
+    // No room left in recv_queue for seq
+    fn is_beyond_recv_window(&self, seq: u32) -> bool {
+        let una_diff = seq.wrapping_sub(self.una.get()) as i32;
+        una_diff >= 0 && una_diff as u32 >= self.recv_window()
+    }
+
+    fn reassemble(&self, packet: Box<UcpPacket>) -> bool {
         let una = self.una.get();
 
-        let una_diff = (packet.seq - una) as i32;
+        let una_diff = packet.seq.wrapping_sub(una) as i32;
         if una_diff < 0 {
-            return;
+            return false;
         }
 
-        let mut pos = 0;
         let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
-        for i in 0..recv_queue.len() {
-            let seq_diff = (packet.seq - recv_queue[i].seq) as i32;
+        let mut pos = recv_queue.len();
+        while pos > 0 {
+            let seq_diff = packet.seq.wrapping_sub(recv_queue[pos - 1].seq) as i32;
 
             if seq_diff == 0 {
-                return;
-            } else if seq_diff < 0 {
+                return false;
+            } else if seq_diff > 0 {
                 break;
             } else {
-                pos += 1;
+                pos -= 1;
             }
         }
 
         recv_queue.insert(pos, packet);
+        debug_assert!(
+            pos == 0 || recv_queue[pos].seq.wrapping_sub(recv_queue[pos - 1].seq) as i32 > 0
+        );
 
+        let una_before = una;
         for i in pos..recv_queue.len() {
             let una = self.una.get();
             if recv_queue[i].seq == una {
-                self.una.set(una + 1);
+                if recv_queue[i].cmd == CMD_FIN {
+                    self.fin_received.set(true);
+                } else if recv_queue[i].cmd == CMD_REKEY {
+                    self.retiring_recv_key.set(None);
+                }
+                self.advance_una(una.wrapping_add(1));
             } else {
                 break;
             }
         }
 
+        // Track how long received packets wait behind a gap, the deadline
+        // check skips the gap once it is too old
+        let una = self.una.get();
+        let waiting = match recv_queue.back() {
+            Some(packet) => packet.seq.wrapping_sub(una) as i32 >= 0,
+            None => false,
+        };
+        let released = una.wrapping_sub(una_before) > 1;
+
+        match self.gap_time.get() {
+            Some(gap_time) if released => {
+                self.reorder_delayed(gap_time);
+                self.gap_time
+                    .set(if waiting { Some(Instant::now()) } else { None });
+            }
+            None if waiting => self.gap_time.set(Some(Instant::now())),
+            _ => {}
+        }
+
         self.try_wake_reader();
+        true
+    }
+
+    fn is_syn_outstanding(&self, seq: u32) -> bool {
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        send_queue
+            .iter()
+            .any(|packet| packet.cmd == CMD_SYN && packet.seq == seq)
     }
 
     async fn process_syn_ack(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_SYN_ACK && packet.payload == 8 {
+        if packet.cmd == CMD_SYN_ACK && (packet.payload == 8 || packet.payload == 12) {
             let seq = packet.payload_read_u32();
             let timestamp = packet.payload_read_u32();
+            let features = if packet.payload == 12 {
+                packet.payload_read_u32() & self.config().features
+            } else {
+                0
+            };
+
+            // A SYN_ACK must echo our outstanding SYN, or once established be
+            // a resend of the one we already took, anything else is forged
+            let echoed = match self.state.get() {
+                UcpState::CONNECTING => self.is_syn_outstanding(seq),
+                _ => (packet.seq.wrapping_sub(self.una.get()) as i32) < 0,
+            };
+
+            if !echoed {
+                error!(
+                    "unexpect SYN_ACK from {}, echo seq: {}, session: {}",
+                    self.remote_addr.get(),
+                    seq,
+                    self.session_id.get()
+                );
+                return;
+            }
 
-            let mut ack = self.new_noseq_packet(CMD_ACK);
-            ack.payload_write_u32(packet.seq);
-            ack.payload_write_u32(packet.timestamp);
-            self.send_packet_directly(&mut ack).await;
-
-            match self.state.get() {
+            let established = match self.state.get() {
                 UcpState::CONNECTING => {
-                    if self.process_an_ack(seq, timestamp) {
-                        self.state.set(UcpState::ESTABLISHED);
-                        self.una.set(packet.seq + 1);
+                    let acked = self.process_an_ack(seq, timestamp);
+                    if acked {
+                        self.set_state(UcpState::ESTABLISHED, "SYN_ACK");
+                        self.una.set(packet.seq.wrapping_add(1));
+                        self.set_features(features);
                         info!(
                             "{} established, session: {}",
-                            self.remote_addr,
+                            self.remote_addr.get(),
                             self.session_id.get()
                         );
                     }
+                    acked
                 }
-                _ => {}
+                _ => false,
+            };
+
+            // With data queued, its una acks the SYN_ACK and saves a packet
+            let mut packets = UcpPacketQueue::new();
+            if established {
+                self.send_pending_packets(&mut packets);
+            }
+
+            if packets.is_empty() {
+                let mut ack = self.new_noseq_packet(CMD_ACK);
+                ack.payload_write_u32(packet.seq);
+                ack.payload_write_u32(packet.timestamp);
+                self.send_packet_directly(&mut ack).await;
+            } else {
+                self.send_packets(packets).await;
             }
         } else {
             error!(
@@ -706,27 +2328,67 @@ impl InnerStream {
         }
     }
 
-    async fn process_heartbeat(&self) {
+    async fn process_heartbeat(&self, packet: Box<UcpPacket>) {
         let mut heartbeat_ack = self.new_noseq_packet(CMD_HEARTBEAT_ACK);
+        heartbeat_ack.payload_write_u32(packet.timestamp);
         self.send_packet_directly(&mut heartbeat_ack).await;
     }
 
-    fn process_heartbeat_ack(&self) {
+    fn process_heartbeat_ack(&self, mut packet: Box<UcpPacket>) {
         self.alive_time.set(Instant::now());
+
+        // Heartbeats are never resent, so the echoed timestamp is a clean
+        // sample even on streams with little data to sample from. Older
+        // peers echo nothing
+        if packet.payload == 4 {
+            let rtt = self.timestamp().wrapping_sub(packet.payload_read_u32());
+            if rtt as i32 >= 0 {
+                self.update_rto(rtt);
+            }
+        }
     }
 
-    fn process_an_ack(&self, seq: u32, timestamp: u32) -> bool {
-        let rtt = self.timestamp() - timestamp;
-        self.update_rto(rtt);
+    fn process_rst(&self, mut packet: Box<UcpPacket>) {
+        let reason = if packet.payload == 4 {
+            packet.payload_read_u32()
+        } else {
+            0
+        };
+
+        error!(
+            "{} reset connection, session: {}, reason: {}",
+            self.remote_addr.get(),
+            self.session_id.get(),
+            reason
+        );
+        self.error.set(Some(UcpError::Rejected(reason)));
+        self.die();
+    }
 
+    fn process_an_ack(&self, seq: u32, timestamp: u32) -> bool {
         let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
         for i in 0..send_queue.len() {
             if send_queue[i].seq == seq {
-                send_queue.remove(i);
+                if let Some(packet) = send_queue.remove(i) {
+                    // Karn: an ack of a resent packet is an ambiguous sample,
+                    // and an echoed timestamp ahead of our clock is bogus
+                    let rtt = self.timestamp().wrapping_sub(timestamp);
+                    if packet.xmit == 0 && rtt as i32 >= 0 {
+                        self.update_rto(rtt);
+                    }
+
+                    self.packet_delivered(&packet);
+                    self.packets_acked(seq, seq);
+
+                    if packet.cmd == CMD_DATA {
+                        let sacked = unsafe { &mut *self.sacked.as_ptr() };
+                        sacked.push((seq, packet.payload as u32));
+                    }
+                }
                 return true;
             } else {
                 if send_queue[i].timestamp <= timestamp {
-                    send_queue[i].skip_times += 1;
+                    send_queue[i].skip_times = send_queue[i].skip_times.saturating_add(1);
                 }
             }
         }
@@ -734,34 +2396,90 @@ impl InnerStream {
         false
     }
 
-    fn update_rto(&self, rtt: u32) {
-        // The calculation accuracy is milliseconds
-        let mut srtt = self.srtt.get();
-        if srtt == 0 {
-            srtt = rtt;
+    fn packet_delivered(&self, packet: &UcpPacket) {
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        congestion.on_ack(1);
+
+        if packet.cmd == CMD_DATA {
+            self.delivered
+                .set(self.delivered.get() + packet.payload as u64);
+        }
+    }
+
+    fn bytes_delivered(&self, packet: &UcpPacket) {
+        if packet.cmd == CMD_DATA {
+            self.metrics
+                .delivered_bytes
+                .fetch_add(packet.payload as u64, Ordering::Relaxed);
         }
-        srtt = (srtt * 9 + rtt) / 10;
+    }
+
+    fn update_rto(&self, rtt: u32) {
+        // Samples come from timestamps the peer echoes, keep a bogus one
+        // from overflowing the estimator
+        let rtt = min(rtt, MAX_RTO);
+
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        congestion.on_rtt_sample(rtt);
 
-        let mut rttvar = self.rttvar.get();
-        let delta = if rtt > srtt { rtt - srtt } else { srtt - rtt };
-        rttvar = (rttvar * 3 + delta) / 4;
+        // The first sample, a 0ms srtt is a valid estimate
+        let first = self.min_rtt.get().is_none();
+        let min_rtt = self.min_rtt.get().map_or(rtt, |min_rtt| min(min_rtt, rtt));
+        self.min_rtt.set(Some(min_rtt));
+
+        // The calculation accuracy is milliseconds, as RFC 6298
+        let (srtt, rttvar) = if first {
+            (rtt, rtt / 2)
+        } else {
+            let srtt = self.srtt.get();
+            let delta = rtt.abs_diff(srtt);
+            (
+                srtt.saturating_mul(7).saturating_add(rtt) / 8,
+                self.rttvar.get().saturating_mul(3).saturating_add(delta) / 4,
+            )
+        };
 
-        let rto = srtt + 4 * rttvar;
+        let rto = self.clamp_rto(srtt.saturating_add(rttvar.saturating_mul(4)));
 
         self.rto.set(rto);
         self.srtt.set(srtt);
         self.rttvar.set(rttvar);
     }
 
+    fn clamp_rto(&self, rto: u32) -> u32 {
+        let config = self.config();
+        rto.max(config.min_rto.unwrap_or(MIN_RTO))
+            .min(config.max_rto.unwrap_or(MAX_RTO))
+    }
+
+    fn update_one_way_delay(&self, sent_timestamp: u32, remote_timestamp: u32) {
+        // Both timestamps are relative to each side's own start time, so the
+        // delays include the clock offset, only the trend of each is meaningful
+        let send_delay = remote_timestamp.wrapping_sub(sent_timestamp) as i32;
+        let recv_delay = self.timestamp().wrapping_sub(remote_timestamp) as i32;
+
+        let smooth = |old: Option<i32>, delay: i32| match old {
+            Some(old) => Some(old.wrapping_add(delay.wrapping_sub(old) / 8)),
+            None => Some(delay),
+        };
+
+        self.send_delay
+            .set(smooth(self.send_delay.get(), send_delay));
+        self.recv_delay
+            .set(smooth(self.recv_delay.get(), recv_delay));
+    }
+
     fn new_packet(&self, cmd: u8) -> Box<UcpPacket> {
         let mut packet = Box::new(UcpPacket::new());
 
+        packet.limit = self.packet_limit();
         packet.session_id = self.session_id.get();
         packet.timestamp = self.timestamp();
-        packet.window = self.local_window.get();
+        packet.window = self.recv_window();
         packet.seq = self.next_seq();
         packet.una = self.una.get();
         packet.cmd = cmd;
+        packet.epoch = self.send_epoch.get();
 
         packet
     }
@@ -769,11 +2487,13 @@ impl InnerStream {
     fn new_noseq_packet(&self, cmd: u8) -> Box<UcpPacket> {
         let mut packet = Box::new(UcpPacket::new());
 
+        packet.limit = self.packet_limit();
         packet.session_id = self.session_id.get();
         packet.timestamp = self.timestamp();
-        packet.window = self.local_window.get();
+        packet.window = self.recv_window();
         packet.una = self.una.get();
         packet.cmd = cmd;
+        packet.epoch = self.send_epoch.get();
 
         packet
     }
@@ -784,36 +2504,858 @@ impl InnerStream {
 
     fn next_seq(&self) -> u32 {
         let seq = unsafe { &mut *self.seq.as_ptr() };
-        *seq += 1;
+        *seq = seq.wrapping_add(1);
         *seq
     }
 
-    fn make_packet_send(&self, buf: &[u8]) {
+    fn make_packet_send(&self, buf: &[u8]) -> usize {
         let buf_len = buf.len();
 
         let mut pos = 0;
-        while pos < buf_len {
+        while pos < buf_len && !self.is_send_buffer_overflow() {
             let mut packet = self.new_packet(CMD_DATA);
             let size = min(packet.remaining_load(), buf_len - pos);
             let end_pos = pos + size;
 
             packet.payload_write_slice(&buf[pos..end_pos]);
-            self.send_packet(packet);
+            self.enqueue_packet(packet);
 
             pos = end_pos;
         }
+
+        pos
     }
 
-    fn send_packet(&self, packet: Box<UcpPacket>) {
+    fn enqueue_packet(&self, packet: Box<UcpPacket>) {
         let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
         send_buffer.push_back(packet);
     }
 
+    async fn send_packets(&self, packets: UcpPacketQueue) {
+        if self.features.get() & UCP_FEATURE_BATCH == 0 {
+            for mut packet in packets.into_iter() {
+                self.send_packet_directly(&mut packet).await;
+            }
+            return;
+        }
+
+        let capacity = self.new_noseq_packet(CMD_BATCH).remaining_load();
+        let mut batch = UcpPacketQueue::new();
+        let mut batch_size = 0;
+
+        for mut packet in packets.into_iter() {
+            self.pack(&mut packet);
+
+            let size = packet.size + 2;
+            if batch_size + size > capacity {
+                self.send_batch(&mut batch).await;
+                batch_size = 0;
+            }
+
+            batch_size += size;
+            batch.push_back(packet);
+        }
+
+        self.send_batch(&mut batch).await;
+    }
+
+    async fn send_batch(&self, batch: &mut UcpPacketQueue) {
+        // Already packed, packing again would seal the payload twice
+        if batch.len() <= 1 {
+            if let Some(packet) = batch.pop_front() {
+                self.packet_sent(&packet);
+                self.send_datagram(packet.packed_buffer()).await;
+            }
+            return;
+        }
+
+        let mut packet = self.new_noseq_packet(CMD_BATCH);
+        for p in batch.drain(..) {
+            self.packet_sent(&p);
+            packet.payload_write_slice(&(p.size as u16).to_be_bytes());
+            packet.payload_write_slice(p.packed_buffer());
+        }
+
+        self.pack(&mut packet);
+        self.send_datagram(packet.packed_buffer()).await;
+    }
+
+    fn unbatch(&self, mut packet: Box<UcpPacket>) -> UcpPacketQueue {
+        let mut packets = UcpPacketQueue::new();
+        let mut len = [0u8; 2];
+
+        while packet.payload_read_slice(&mut len) == 2 {
+            let size = u16::from_be_bytes(len) as usize;
+            if size > packet.payload_remaining() {
+                error!("truncated packet in batch from {}", self.remote_addr.get());
+                break;
+            }
+
+            let mut p = Box::new(UcpPacket::new());
+            p.size = packet.payload_read_slice(&mut p.buf[..size]);
+            if p.parse(self.config(), |p| self.peer_key(p)) && p.cmd != CMD_BATCH {
+                packets.push_back(p);
+            }
+        }
+
+        packets
+    }
+
+    fn acquire_send(&self) -> bool {
+        match self.limiter {
+            Some(ref limiter) if !limiter.try_acquire() => {
+                self.metrics.throttled.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn packet_sent(&self, packet: &UcpPacket) {
+        self.heartbeat.set(Instant::now());
+        self.metrics.sent_packets.fetch_add(1, Ordering::Relaxed);
+
+        match packet.cmd {
+            CMD_DATA => {
+                self.metrics
+                    .sent_bytes
+                    .fetch_add(packet.payload as u64, Ordering::Relaxed);
+            }
+            // Already took a token through acquire_send
+            CMD_SYN | CMD_SYN_ACK | CMD_FIN | CMD_MTU_PROBE => {}
+            _ => {
+                if let Some(ref limiter) = self.limiter {
+                    limiter.consume();
+                }
+            }
+        }
+    }
+
     async fn send_packet_directly(&self, packet: &mut Box<UcpPacket>) {
-        packet.pack();
-        let _ = self
-            .socket
-            .send_to(packet.packed_buffer(), self.remote_addr)
+        self.packet_sent(packet);
+
+        self.pack(packet);
+        self.send_datagram(packet.packed_buffer()).await;
+    }
+
+    fn pack(&self, packet: &mut UcpPacket) {
+        let key = self.sealing_key(packet);
+        packet.pack(self.config(), key.as_ref());
+    }
+
+    // Packets are sealed in the epoch they were made in, seqs before the
+    // REKEY under the previous key, however late they are sent or resent
+    fn sealing_key(&self, packet: &mut UcpPacket) -> Option<UcpKey> {
+        self.config().crypto.as_ref()?;
+        self.sealed_packets.set(self.sealed_packets.get() + 1);
+
+        let epoch = self.send_epoch.get();
+        if packet.epoch.wrapping_add(1) == epoch {
+            if let Some(key) = self.retiring_key.get() {
+                return Some(key);
+            }
+        }
+
+        packet.epoch = epoch;
+        match self.send_key.get() {
+            Some(key) => Some(key),
+            None => self.packet_key(packet.session_id),
+        }
+    }
+
+    // The peer's key of our epoch for it, the next one once it rekeyed, or
+    // the previous one until una passes its REKEY. Another session is a SYN
+    // nothing but the session key seals
+    fn peer_key(&self, packet: &UcpPacket) -> Option<UcpKey> {
+        let crypto = self.config().crypto.as_ref()?;
+
+        if packet.session_id != self.session_id.get() {
+            return match packet.epoch {
+                0 => self.packet_key(packet.session_id),
+                _ => None,
+            };
+        }
+
+        let epoch = self.recv_epoch.get();
+        let key = match self.recv_key.get() {
+            Some(key) => key,
+            None => self.packet_key(packet.session_id)?,
+        };
+
+        if packet.epoch == epoch {
+            Some(key)
+        } else if packet.epoch == epoch.wrapping_add(1) {
+            Some(crypto.next_key(&key))
+        } else if packet.epoch.wrapping_add(1) == epoch {
+            self.retiring_recv_key.get()
+        } else {
+            None
+        }
+    }
+
+    // The first packet opened with the peer's next key moves us to it
+    fn check_peer_rekeyed(&self, packet: &UcpPacket) {
+        let crypto = match self.config().crypto {
+            Some(ref crypto) => crypto,
+            None => return,
+        };
+
+        let epoch = self.recv_epoch.get().wrapping_add(1);
+        if packet.session_id != self.session_id.get() || packet.epoch != epoch {
+            return;
+        }
+
+        let key = match self.recv_key.get() {
+            Some(key) => key,
+            None => match self.packet_key(packet.session_id) {
+                Some(key) => key,
+                None => return,
+            },
+        };
+
+        self.retiring_recv_key.set(Some(key));
+        self.recv_key.set(Some(crypto.next_key(&key)));
+        self.recv_epoch.set(epoch);
+    }
+
+    // Moves what we send to the next key once the current one sealed
+    // rekey_packets, the previous one is dropped once all made before the
+    // REKEY is acked
+    fn check_rekey(&self) {
+        if self.config().crypto.is_none() {
+            return;
+        }
+
+        if self.retiring_key.get().is_some() {
+            let send_queue = unsafe { &*self.send_queue.as_ptr() };
+            let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+            let epoch = self.send_epoch.get();
+
+            if send_queue
+                .iter()
+                .chain(send_buffer.iter())
+                .all(|packet| packet.epoch == epoch)
+            {
+                self.retiring_key.set(None);
+            }
+            return;
+        }
+
+        let limit = self.config().rekey_packets.unwrap_or(UCP_REKEY_PACKETS);
+        if self.state.get() == UcpState::ESTABLISHED && self.sealed_packets.get() >= limit {
+            self.rekey();
+        }
+    }
+
+    fn rekey(&self) {
+        let crypto = match self.config().crypto {
+            Some(ref crypto) => crypto,
+            None => return,
+        };
+
+        let key = match self.send_key.get() {
+            Some(key) => key,
+            None => match self.packet_key(self.session_id.get()) {
+                Some(key) => key,
+                None => return,
+            },
+        };
+
+        self.retiring_key.set(Some(key));
+        self.send_key.set(Some(crypto.next_key(&key)));
+        self.send_epoch.set(self.send_epoch.get().wrapping_add(1));
+        self.sealed_packets.set(0);
+
+        // Seqs before it stay under the old key, once the peer's una passes
+        // it the old key has nothing left to open
+        let rekey = self.new_packet(CMD_REKEY);
+        self.enqueue_packet(rekey);
+
+        info!(
+            "{} rekeyed to epoch {}, session: {}",
+            self.remote_addr.get(),
+            self.send_epoch.get(),
+            self.session_id.get()
+        );
+    }
+
+    fn packet_key(&self, session_id: u32) -> Option<UcpKey> {
+        let crypto = self.config().crypto.as_ref()?;
+
+        match self.session_key.get() {
+            Some((id, key)) if id == session_id => Some(key),
+            _ => {
+                let key = crypto.session_key(session_id);
+                self.session_key.set(Some((session_id, key)));
+                Some(key)
+            }
+        }
+    }
+
+    pub(super) fn capture(&self, _datagram: &[u8]) {
+        #[cfg(feature = "pcap")]
+        {
+            if let Some(ref capture) = self.config().capture {
+                capture.record(_datagram);
+            }
+        }
+    }
+
+    async fn send_datagram(&self, buf: &[u8]) {
+        for _ in 0..SEND_RETRY_TIMES {
+            match self.socket.send_to(buf, self.remote_addr.get()).await {
+                Ok(size) if size == buf.len() => {
+                    self.capture(buf);
+                    return;
+                }
+                Ok(size) => {
+                    warn!(
+                        "short send to {}, {} of {} bytes",
+                        self.remote_addr.get(),
+                        size,
+                        buf.len()
+                    );
+                }
+                Err(e) => {
+                    error!("send to {} failed: {}", self.remote_addr.get(), e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn same_arc<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::harness::*;
+
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::task;
+
+    // A stream past the handshake, fed packets by hand instead of a peer
+    fn established(config: UcpConfig) -> InnerStream {
+        limited(config, None)
+    }
+
+    fn limited(config: UcpConfig, limiter: Option<Arc<RateLimiter>>) -> InnerStream {
+        let (link, _) = LoopbackTransport::pair(client_addr(), server_addr());
+        let stream = InnerStream::new(
+            link,
+            server_addr(),
+            Arc::new(UcpStreamMetrics::new()),
+            config,
+            limiter,
+        );
+        stream.session_id.set(1);
+        stream.state.set(UcpState::ESTABLISHED);
+        stream
+    }
+
+    // A DATA packet as the stream would receive it
+    fn data(stream: &InnerStream, seq: u32, payload: &[u8]) -> Box<UcpPacket> {
+        let mut packet = stream.new_noseq_packet(CMD_DATA);
+        packet.seq = seq;
+        packet.payload_write_slice(payload);
+        stream.pack(&mut packet);
+        assert!(packet.parse(stream.config(), |p| stream.peer_key(p)));
+        packet
+    }
+
+    // An ACK from the peer for seq, which leaves una where it is
+    fn ack(stream: &InnerStream, una: u32, seq: u32) -> Box<UcpPacket> {
+        let mut packet = stream.new_noseq_packet(CMD_ACK);
+        packet.una = una;
+        packet.payload_write_u32(seq);
+        packet.payload_write_u32(stream.timestamp());
+        stream.pack(&mut packet);
+        assert!(packet.parse(stream.config(), |p| stream.peer_key(p)));
+        packet
+    }
+
+    fn sealed_config(rekey_packets: Option<u64>) -> UcpConfig {
+        UcpConfig {
+            crypto: Some(Arc::new(ChaChaPolyCrypto::new(b"secret"))),
+            rekey_packets,
+            ..Default::default()
+        }
+    }
+
+    // A DATA packet from stream's session as the peer would receive it,
+    // sealed with key however the stream's own keys are
+    fn sealed_data(stream: &InnerStream, epoch: u32, key: &UcpKey) -> UcpPacket {
+        let mut packet = stream.new_noseq_packet(CMD_DATA);
+        packet.payload_write_slice(b"data");
+        packet.epoch = epoch;
+        packet.pack(stream.config(), Some(key));
+
+        let mut received = UcpPacket::new();
+        received.buf[..packet.size].copy_from_slice(packet.packed_buffer());
+        received.size = packet.size;
+        received
+    }
+
+    fn locked<T, F: FnOnce(&InnerStream) -> T>(stream: &InnerStream, f: F) -> T {
+        let _l = stream.lock();
+        f(stream)
+    }
+
+    fn read_all(stream: &InnerStream) -> Vec<u8> {
+        let mut buf = [0u8; 4096];
+        match stream.try_read(&mut buf) {
+            Ok(size) => buf[..size].to_vec(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn out_of_order_bytes() {
+        let stream = established(UcpConfig::default());
+        stream.una.set(1);
+
+        stream.process_data(data(&stream, 1, b"one"));
+        stream.process_data(data(&stream, 3, b"three"));
+        assert_eq!(stream.out_of_order_bytes(), 5);
+        assert_eq!(read_all(&stream), b"one");
+        assert_eq!(stream.out_of_order_bytes(), 5);
+
+        stream.process_data(data(&stream, 2, b"two"));
+        assert_eq!(stream.out_of_order_bytes(), 0);
+        assert_eq!(read_all(&stream), b"twothree");
+    }
+
+    #[test]
+    fn inject_recovered_data() {
+        let stream = established(UcpConfig::default());
+        stream.una.set(1);
+
+        stream.process_data(data(&stream, 1, b"one"));
+        stream.process_data(data(&stream, 3, b"three"));
+        assert!(stream.inject_data(2, b"two"));
+        assert!(!stream.inject_data(2, b"two"));
+        assert!(!stream.inject_data(1, b"one"));
+
+        assert_eq!(stream.una.get(), 4);
+        assert_eq!(read_all(&stream), b"onetwothree");
+    }
+
+    #[test]
+    fn inject_within_recv_window() {
+        let stream = established(UcpConfig {
+            recv_window: Some(4),
+            ..Default::default()
+        });
+
+        assert!(!stream.inject_data(4, b"beyond"));
+        assert!(stream.inject_data(3, b"last"));
+
+        // Delivered but unread packets take their room until read
+        for seq in 0..3 {
+            assert!(stream.inject_data(seq, b"data"));
+        }
+        assert_eq!(stream.recv_window(), 0);
+        assert!(!stream.inject_data(4, b"full"));
+
+        read_all(&stream);
+        assert!(stream.inject_data(4, b"room"));
+        assert_eq!(read_all(&stream), b"room");
+    }
+
+    #[test]
+    fn rekey_retires_the_old_key() {
+        task::block_on(async {
+            let harness = TestHarness::new(sealed_config(None));
+            let (client, server) = harness.establish(sealed_config(None)).await;
+
+            let crypto = ChaChaPolyCrypto::new(b"secret");
+            let old = crypto.session_key(client.session_id());
+            let new = crypto.next_key(&old);
+
+            let sent = pattern(4, 64 << 10);
+            assert_eq!(transfer(&client, &server, &sent).await, sent);
+
+            locked(&client.inner, |inner| inner.rekey());
+            assert_eq!(transfer(&client, &server, &sent).await, sent);
+
+            // The old key goes once the REKEY and all before it are acked
+            let retired = wait_until(Duration::from_secs(5), || {
+                locked(&client.inner, |inner| inner.retiring_key.get().is_none())
+            })
+            .await;
+            assert!(retired);
+
+            locked(&server.inner, |inner| {
+                assert_eq!(inner.recv_epoch.get(), 1);
+                assert_eq!(inner.recv_key.get(), Some(new));
+                assert!(inner.retiring_recv_key.get().is_none());
+                assert_eq!(inner.send_epoch.get(), 0);
+            });
+
+            let opens = |mut packet: UcpPacket| {
+                packet.parse(server.inner.config(), |p| server.inner.opening_key(p))
+            };
+            assert!(opens(sealed_data(&client.inner, 1, &new)));
+            assert!(!opens(sealed_data(&client.inner, 0, &old)));
+            assert!(!opens(sealed_data(&client.inner, 1, &old)));
+
+            // The other direction still uses the session key
+            assert_eq!(transfer(&server, &client, &sent).await, sent);
+            assert_eq!(locked(&client.inner, |inner| inner.recv_epoch.get()), 0);
+        });
+    }
+
+    #[test]
+    fn rekeys_under_loss() {
+        task::block_on(async {
+            // A small window, so the packets made before a REKEY are acked
+            // soon and the next rekey can start
+            let config = UcpConfig {
+                recv_window: Some(64),
+                ..sealed_config(Some(64))
+            };
+            let harness = TestHarness::new(config.clone());
+            let (client, server) = harness.establish(config).await;
+
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_drop_rate(0.05);
+                link.set_delay(Duration::from_millis(5), Duration::from_millis(10));
+            }
+
+            let sent = pattern(5, 1 << 20);
+            let received = transfer(&client, &server, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            let received = transfer(&server, &client, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            // Some 800 packets each way
+            let client_epoch = locked(&client.inner, |inner| inner.send_epoch.get());
+            let server_epoch = locked(&server.inner, |inner| inner.send_epoch.get());
+            assert!(client_epoch >= 5, "client epoch {}", client_epoch);
+            assert!(server_epoch >= 5, "server epoch {}", server_epoch);
+        });
+    }
+
+    #[test]
+    fn negotiates_common_features() {
+        task::block_on(async {
+            let offering = |features| UcpConfig {
+                features,
+                ..Default::default()
+            };
+
+            // The client offers batching and probing, the server batching
+            let harness = TestHarness::new(offering(UCP_FEATURE_BATCH));
+            let (client, server) = harness
+                .establish(offering(UCP_FEATURE_BATCH | UCP_FEATURE_MTU_PROBE))
+                .await;
+
+            assert_eq!(client.features(), UCP_FEATURE_BATCH);
+            assert_eq!(server.features(), UCP_FEATURE_BATCH);
+            // Without probing agreed on the client doesn't start small
+            assert_eq!(
+                locked(&client.inner, |inner| inner.path_mtu.get()),
+                UCP_PACKET_SIZE
+            );
+
+            let sent = pattern(6, 64 << 10);
+            assert_eq!(transfer(&client, &server, &sent).await, sent);
+
+            // Offering nothing, the default, enables nothing
+            let harness = TestHarness::new(offering(UCP_FEATURE_BATCH | UCP_FEATURE_MTU_PROBE));
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            assert_eq!(client.features(), 0);
+            assert_eq!(server.features(), 0);
+        });
+    }
+
+    #[test]
+    fn abandons_expired_data() {
+        task::block_on(async {
+            let config = UcpConfig {
+                max_packet_age: Some(200),
+                ..Default::default()
+            };
+            let harness = TestHarness::new(config.clone());
+            let (client, server) = harness.establish(config).await;
+
+            // Lost however often it is resent, until it expires
+            harness.client_link.set_drop_rate(1.0);
+            (&client).write_all(b"stale").await.unwrap();
+
+            let metrics = client.metrics();
+            let abandoned =
+                wait_until(Duration::from_secs(5), || metrics.get_abandoned() == 1).await;
+            assert!(abandoned);
+
+            harness.client_link.set_drop_rate(0.0);
+            (&client).write_all(b"fresh").await.unwrap();
+
+            // The gap it leaves doesn't hold up what follows
+            let mut buf = [0u8; 5];
+            (&server).read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"fresh");
+
+            let drained = wait_until(Duration::from_secs(5), || {
+                locked(&client.inner, |inner| unsafe {
+                    (*inner.send_queue.as_ptr()).is_empty()
+                })
+            })
             .await;
+            assert!(drained);
+            assert_eq!(metrics.get_abandoned(), 1);
+        });
+    }
+
+    #[test]
+    fn stalls_only_while_the_peer_acks() {
+        let config = UcpConfig {
+            stall_timeout: Some(100),
+            ..Default::default()
+        };
+        let stuck = established(config.clone());
+        let silent = established(config);
+
+        for stream in [&stuck, &silent].iter() {
+            let send_queue = unsafe { &mut *stream.send_queue.as_ptr() };
+            send_queue.push_back(stream.new_packet(CMD_DATA));
+            send_queue.push_back(stream.new_packet(CMD_DATA));
+            assert!(!stream.check_if_stalled());
+        }
+
+        // The peer keeps acking the second packet but never gets the first
+        let first = unsafe { &*stuck.send_queue.as_ptr() }[0].seq;
+        stuck.process_una(first);
+        stuck.process_ack(ack(&stuck, first, first.wrapping_add(1)));
+        assert!(!stuck.check_if_stalled());
+
+        std::thread::sleep(Duration::from_millis(150));
+        stuck.process_una(first);
+        stuck.process_ack(ack(&stuck, first, first.wrapping_add(1)));
+        assert!(stuck.check_if_stalled());
+
+        // Nothing from the peer is a dead link, left to the idle timeout
+        assert!(!silent.check_if_stalled());
+
+        // Progress on una starts over
+        stuck.process_una(first.wrapping_add(1));
+        assert!(!stuck.check_if_stalled());
+    }
+
+    #[test]
+    fn sessions_share_the_packet_rate() {
+        // As a listener with max_packet_rate 1000 hands it to every session
+        let limiter = Arc::new(RateLimiter::new(1000, Some(10)));
+        let streams: Vec<_> = (0..2)
+            .map(|_| limited(UcpConfig::default(), Some(limiter.clone())))
+            .collect();
+        let mut sent = [0usize; 2];
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            for (stream, sent) in streams.iter().zip(sent.iter_mut()) {
+                if stream.acquire_send() {
+                    *sent += 1;
+                }
+            }
+        }
+        let elapsed = start.elapsed().as_millis() as usize;
+
+        // The burst, then one packet a milli between them
+        let total = sent[0] + sent[1];
+        assert!(
+            total <= 10 + elapsed + 1,
+            "{} packets in {}ms",
+            total,
+            elapsed
+        );
+        assert!(total >= elapsed / 2, "{} packets in {}ms", total, elapsed);
+
+        for (stream, sent) in streams.iter().zip(sent.iter()) {
+            assert!(*sent > 0);
+            assert!(stream.metrics.get_throttled() > 0);
+        }
+    }
+
+    fn reno_config(initial_ssthresh: Option<u32>) -> UcpConfig {
+        let reno: CongestionControlFn =
+            Arc::new(|ssthresh| Box::new(RenoCongestionControl::new(ssthresh)));
+        UcpConfig {
+            congestion_control: Some(reno),
+            initial_ssthresh,
+            ..Default::default()
+        }
+    }
+
+    // cwnd after a lossless transfer of 1MB, growing from initial_ssthresh
+    async fn cwnd_after_transfer(initial_ssthresh: Option<u32>) -> u32 {
+        // No spurious resend timeout on a busy test machine
+        let config = UcpConfig {
+            min_rto: Some(1000),
+            ..reno_config(initial_ssthresh)
+        };
+        let harness = TestHarness::new(config.clone());
+        let (client, server) = harness.establish(config).await;
+
+        let sent = pattern(4, 1 << 20);
+        let received = transfer(&client, &server, &sent).await;
+        assert_eq!(first_divergence(&sent, &received), None);
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.get_resent_packets(), 0);
+        locked(&client.inner, |inner| inner.update_metrics());
+        metrics.get_cwnd()
+    }
+
+    #[test]
+    fn initial_ssthresh_ends_slow_start() {
+        task::block_on(async {
+            // Slow start adds a packet per ack, congestion avoidance one per
+            // window of acks
+            let slow_start = cwnd_after_transfer(None).await;
+            assert!(slow_start > 256, "cwnd {}", slow_start);
+
+            let avoidance = cwnd_after_transfer(Some(8)).await;
+            assert!(avoidance > 8 && avoidance < 64, "cwnd {}", avoidance);
+        });
+    }
+
+    #[test]
+    fn rto_follows_rfc6298() {
+        let stream = established(UcpConfig::default());
+        assert_eq!(stream.rto.get(), DEFAULT_RTO);
+
+        // SRTT = R, RTTVAR = R/2, RTO = SRTT + 4 * RTTVAR
+        stream.update_rto(100);
+        assert_eq!((stream.srtt.get(), stream.rttvar.get()), (100, 50));
+        assert_eq!(stream.rto.get(), 300);
+
+        // RTTVAR = 3/4 RTTVAR + 1/4 |SRTT - R| with the old SRTT, then
+        // SRTT = 7/8 SRTT + 1/8 R
+        stream.update_rto(200);
+        assert_eq!((stream.srtt.get(), stream.rttvar.get()), (112, 62));
+        assert_eq!(stream.rto.get(), 112 + 4 * 62);
+
+        let clamped = established(UcpConfig {
+            min_rto: Some(500),
+            max_rto: Some(1000),
+            ..Default::default()
+        });
+        clamped.update_rto(10);
+        assert_eq!(clamped.rto.get(), 500);
+        clamped.update_rto(5000);
+        assert_eq!(clamped.rto.get(), 1000);
+    }
+
+    #[test]
+    fn resent_packets_give_no_rtt_sample() {
+        let stream = established(UcpConfig::default());
+        let send_queue = unsafe { &mut *stream.send_queue.as_ptr() };
+
+        let mut resent = stream.new_packet(CMD_DATA);
+        resent.xmit = 1;
+        let resent_seq = resent.seq;
+        send_queue.push_back(resent);
+        let sent = stream.new_packet(CMD_DATA);
+        let sent_seq = sent.seq;
+        send_queue.push_back(sent);
+        let future = stream.new_packet(CMD_DATA);
+        let future_seq = future.seq;
+        send_queue.push_back(future);
+
+        // Karn: which transmission the ack answers is ambiguous
+        let timestamp = stream.timestamp().wrapping_sub(80);
+        assert!(stream.process_an_ack(resent_seq, timestamp));
+        assert_eq!(stream.min_rtt.get(), None);
+        assert_eq!(stream.rto.get(), DEFAULT_RTO);
+
+        // An echoed timestamp ahead of our clock is bogus
+        let timestamp = stream.timestamp().wrapping_add(1000);
+        assert!(stream.process_an_ack(future_seq, timestamp));
+        assert_eq!(stream.min_rtt.get(), None);
+
+        let timestamp = stream.timestamp().wrapping_sub(80);
+        assert!(stream.process_an_ack(sent_seq, timestamp));
+        let rtt = stream.min_rtt.get().unwrap();
+        assert!((80..200).contains(&rtt), "rtt {}", rtt);
+        assert_eq!(stream.rto.get(), rtt + 4 * (rtt / 2));
+    }
+
+    #[test]
+    fn cwnd_backs_off_on_loss() {
+        task::block_on(async {
+            let harness = TestHarness::new(reno_config(None));
+            let (client, server) = harness.establish(reno_config(None)).await;
+            let metrics = client.metrics();
+
+            let sent = pattern(7, 256 << 10);
+            let received = transfer(&client, &server, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+            let grown = wait_until(Duration::from_secs(1), || metrics.get_cwnd() > 64).await;
+            assert!(grown, "cwnd {}", metrics.get_cwnd());
+
+            // Resend timeouts restart slow start from a single packet
+            harness.client_link.set_drop_rate(1.0);
+            (&client).write_all(&sent[..10_000]).await.unwrap();
+            let backed_off = wait_until(Duration::from_secs(3), || metrics.get_cwnd() == 1).await;
+            assert!(backed_off, "cwnd {}", metrics.get_cwnd());
+
+            harness.client_link.set_drop_rate(0.0);
+            let mut received = vec![0u8; 10_000];
+            (&server).read_exact(&mut received).await.unwrap();
+            assert_eq!(first_divergence(&sent[..10_000], &received), None);
+            let regrown = wait_until(Duration::from_secs(1), || metrics.get_cwnd() > 1).await;
+            assert!(regrown);
+        });
+    }
+
+    #[test]
+    fn unread_data_is_bounded_by_the_window() {
+        task::block_on(async {
+            let config = UcpConfig {
+                recv_window: Some(32),
+                ..Default::default()
+            };
+            let harness = TestHarness::new(config.clone());
+            let (client, server) = harness.establish(config).await;
+
+            let sent = pattern(8, 1 << 20);
+            let writer = task::spawn({
+                let sent = sent.clone();
+                async move {
+                    (&client).write_all(&sent).await.unwrap();
+                    client
+                }
+            });
+
+            // Nobody reads, the sender stops once the window is full
+            let queued = || {
+                locked(&server.inner, |inner| unsafe {
+                    (*inner.recv_queue.as_ptr()).len()
+                })
+            };
+            let full = wait_until(Duration::from_secs(2), || queued() == 32).await;
+            assert!(full);
+
+            let metrics = server.metrics();
+            let received = metrics.get_recv_bytes();
+            task::sleep(Duration::from_millis(300)).await;
+            assert_eq!(queued(), 32);
+            assert_eq!(metrics.get_recv_bytes(), received);
+            assert!((received as usize) < sent.len() / 10);
+
+            let mut received = vec![0u8; sent.len()];
+            (&server).read_exact(&mut received).await.unwrap();
+            assert_eq!(first_divergence(&sent, &received), None);
+            writer.await;
+        });
     }
 }
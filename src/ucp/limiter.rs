@@ -0,0 +1,49 @@
+use std::cmp::min;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub(super) struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    bucket: Mutex<(u32, Instant)>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(rate: u32, burst: Option<u32>) -> Self {
+        let burst = burst.unwrap_or(rate / 100).max(1);
+
+        RateLimiter {
+            rate,
+            burst,
+            bucket: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    pub(super) fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+
+        if bucket.0 > 0 {
+            bucket.0 -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(super) fn consume(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+        bucket.0 = bucket.0.saturating_sub(1);
+    }
+
+    fn refill(&self, bucket: &mut (u32, Instant)) {
+        let now = Instant::now();
+        let tokens = (now - bucket.1).as_micros() * self.rate as u128 / 1_000_000;
+
+        if tokens > 0 {
+            bucket.0 = min(bucket.0 as u128 + tokens, self.burst as u128) as u32;
+            bucket.1 = now;
+        }
+    }
+}
@@ -1,32 +1,54 @@
 use async_std::io;
-use async_std::net::UdpSocket;
+use async_std::net::{ToSocketAddrs, UdpSocket};
 use async_std::sync::RwLock;
 use async_std::task;
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use crate::ucp::internal::*;
+use crate::ucp::limiter::*;
 use crate::ucp::packet::*;
 use crate::ucp::stream::*;
+use crate::ucp::{UcpConfig, UcpKey, UcpTransportRef, CMD_HEARTBEAT_ACK, CMD_RST};
 
 type UcpStreamMap = HashMap<SocketAddr, Arc<InnerStream>>;
 type UcpStreamMetricsMap = HashMap<SocketAddr, Arc<UcpStreamMetrics>>;
 
 pub struct UcpListenerMetrics {
     metrics_map: RwLock<UcpStreamMetricsMap>,
+    truncated: AtomicUsize,
+    illegal: AtomicUsize,
+    active_streams: AtomicUsize,
 }
 
 impl UcpListenerMetrics {
     pub fn new() -> Self {
         Self {
             metrics_map: RwLock::new(UcpStreamMetricsMap::new()),
+            truncated: AtomicUsize::new(0),
+            illegal: AtomicUsize::new(0),
+            active_streams: AtomicUsize::new(0),
         }
     }
 
+    pub fn get_truncated(&self) -> usize {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    // Datagrams dropped for a bad checksum or header
+    pub fn get_illegal(&self) -> usize {
+        self.illegal.load(Ordering::Relaxed)
+    }
+
+    pub fn get_active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
     pub async fn get_metrics(&self) -> Vec<(SocketAddr, Arc<UcpStreamMetrics>)> {
         let mut result = Vec::new();
         let map = self.metrics_map.read().await;
@@ -43,6 +65,13 @@ impl UcpListenerMetrics {
         map.insert(addr, metrics);
     }
 
+    async fn rekey(&self, addr: &SocketAddr, new_addr: SocketAddr) {
+        let mut map = self.metrics_map.write().await;
+        if let Some(metrics) = map.remove(addr) {
+            map.insert(new_addr, metrics);
+        }
+    }
+
     async fn remove(&self, addr: &SocketAddr) {
         let mut map = self.metrics_map.write().await;
         map.remove(addr);
@@ -50,17 +79,60 @@ impl UcpListenerMetrics {
 }
 
 pub struct UcpListener {
-    socket: Arc<UdpSocket>,
+    socket: UcpTransportRef,
+    config: UcpConfig,
+    limiter: Option<Arc<RateLimiter>>,
+    reset_limiter: Option<RateLimiter>,
     metrics: Arc<UcpListenerMetrics>,
     stream_map: UcpStreamMap,
     timestamp: Instant,
 }
 
 impl UcpListener {
-    pub async fn bind(listen_addr: &str, metrics: Arc<UcpListenerMetrics>) -> Self {
-        let socket = Arc::new(UdpSocket::bind(listen_addr).await.unwrap());
+    pub async fn bind(listen_addr: &str, metrics: Arc<UcpListenerMetrics>) -> io::Result<Self> {
+        UcpListener::bind_with_config(listen_addr, metrics, UcpConfig::default()).await
+    }
+
+    pub async fn bind_with_config(
+        listen_addr: &str,
+        metrics: Arc<UcpListenerMetrics>,
+        config: UcpConfig,
+    ) -> io::Result<Self> {
+        let listen_addr = listen_addr
+            .to_socket_addrs()
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+        let socket = config.bind(listen_addr).await?;
+        Ok(UcpListener::from_socket(socket, metrics, config))
+    }
+
+    pub fn from_socket(
+        socket: UdpSocket,
+        metrics: Arc<UcpListenerMetrics>,
+        config: UcpConfig,
+    ) -> Self {
+        UcpListener::from_transport(Arc::new(socket), metrics, config)
+    }
+
+    pub fn from_transport(
+        socket: UcpTransportRef,
+        metrics: Arc<UcpListenerMetrics>,
+        config: UcpConfig,
+    ) -> Self {
+        let limiter = config
+            .max_packet_rate
+            .map(|rate| Arc::new(RateLimiter::new(rate, config.packet_burst)));
+
+        let reset_limiter = config
+            .reset_unknown
+            .map(|rate| RateLimiter::new(rate, None));
+
         UcpListener {
             socket: socket,
+            config: config,
+            limiter: limiter,
+            reset_limiter: reset_limiter,
             metrics: metrics,
             stream_map: UcpStreamMap::new(),
             timestamp: Instant::now(),
@@ -78,17 +150,28 @@ impl UcpListener {
 
             if let Ok((size, remote_addr)) = result {
                 packet.size = size;
+                self.capture(&packet.buf[..size]);
 
-                if packet.parse() {
+                if packet.is_truncated() {
+                    error!("recv oversized packet from {}", remote_addr);
+                    self.metrics.truncated.fetch_add(1, Ordering::Relaxed);
+                } else if packet.parse(&self.config, |p| self.opening_key(p, remote_addr)) {
                     if let Some(inner) = self.stream_map.get(&remote_addr) {
                         inner.input(packet, remote_addr).await;
-                    } else if packet.is_syn() {
+                    } else if packet.is_syn() && self.is_allowed(remote_addr) {
                         return self.new_stream(packet, remote_addr).await;
+                    } else if packet.is_syn() {
+                        error!("refuse ucp client from {}", remote_addr);
+                        self.reset_unknown(&packet, remote_addr).await;
+                    } else if self.is_allowed(remote_addr) && self.has_session(&packet) {
+                        self.migrate(packet, remote_addr).await;
                     } else {
                         error!("unknown ucp session packet from {}", remote_addr);
+                        self.reset_unknown(&packet, remote_addr).await;
                     }
                 } else {
                     error!("recv illgal packet from {}", remote_addr);
+                    self.metrics.illegal.fetch_add(1, Ordering::Relaxed);
                 }
             }
 
@@ -103,6 +186,8 @@ impl UcpListener {
             self.socket.clone(),
             remote_addr,
             metrics.clone(),
+            self.config.clone(),
+            self.limiter.clone(),
         ));
         inner.input(packet, remote_addr).await;
 
@@ -113,7 +198,101 @@ impl UcpListener {
 
         self.stream_map.insert(remote_addr, inner.clone());
         self.metrics.insert(remote_addr, metrics).await;
-        UcpStream { inner: inner }
+        self.metrics
+            .active_streams
+            .store(self.stream_map.len(), Ordering::Relaxed);
+        UcpStream::new(inner)
+    }
+
+    fn find_session(&self, session_id: u32) -> Option<(SocketAddr, Arc<InnerStream>)> {
+        self.stream_map
+            .iter()
+            .find(|(_, inner)| inner.alive() && inner.session_id() == session_id)
+            .map(|(addr, inner)| (*addr, inner.clone()))
+    }
+
+    fn has_session(&self, packet: &UcpPacket) -> bool {
+        self.find_session(packet.session_id).is_some()
+    }
+
+    // Only the answer to the stream's heartbeat from the new address moves
+    // the session there, anything else from it just triggers that heartbeat
+    async fn migrate(&mut self, packet: Box<UcpPacket>, remote_addr: SocketAddr) {
+        let (addr, inner) = match self.find_session(packet.session_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        if packet.cmd != CMD_HEARTBEAT_ACK {
+            inner.migrate(remote_addr).await;
+            return;
+        }
+
+        inner.input(packet, remote_addr).await;
+        if inner.remote_addr() == remote_addr {
+            self.stream_map.remove(&addr);
+            self.stream_map.insert(remote_addr, inner);
+            self.metrics.rekey(&addr, remote_addr).await;
+        }
+    }
+
+    // Streams keep the keys of their session, also when it moves to another
+    // address. Anything else is a SYN or a packet of a session gone from
+    // this listener, which only the session key of a session never rekeyed
+    // opens
+    fn opening_key(&self, packet: &UcpPacket, remote_addr: SocketAddr) -> Option<UcpKey> {
+        if let Some(inner) = self.stream_map.get(&remote_addr) {
+            return inner.opening_key(packet);
+        }
+
+        if let Some((_, inner)) = self.find_session(packet.session_id) {
+            return inner.opening_key(packet);
+        }
+
+        let crypto = self.config.crypto.as_ref()?;
+        match packet.epoch {
+            0 => Some(crypto.session_key(packet.session_id)),
+            _ => None,
+        }
+    }
+
+    fn is_allowed(&self, remote_addr: SocketAddr) -> bool {
+        match self.config.address_filter {
+            Some(ref address_filter) => address_filter(remote_addr),
+            None => true,
+        }
+    }
+
+    fn capture(&self, _datagram: &[u8]) {
+        #[cfg(feature = "pcap")]
+        {
+            if let Some(ref capture) = self.config.capture {
+                capture.record(_datagram);
+            }
+        }
+    }
+
+    async fn reset_unknown(&self, packet: &UcpPacket, remote_addr: SocketAddr) {
+        if packet.cmd == CMD_RST {
+            return;
+        }
+
+        if let Some(ref limiter) = self.reset_limiter {
+            if !limiter.try_acquire() {
+                return;
+            }
+
+            let mut rst = UcpPacket::new();
+            rst.session_id = packet.session_id;
+            rst.cmd = CMD_RST;
+            let key = self
+                .config
+                .crypto
+                .as_ref()
+                .map(|crypto| crypto.session_key(packet.session_id));
+            rst.pack(&self.config, key.as_ref());
+            let _ = self.socket.send_to(rst.packed_buffer(), remote_addr).await;
+        }
     }
 
     async fn remove_dead_stream(&mut self) {
@@ -135,6 +314,10 @@ impl UcpListener {
             self.metrics.remove(addr).await;
         }
 
+        self.metrics
+            .active_streams
+            .store(self.stream_map.len(), Ordering::Relaxed);
+
         self.timestamp = now;
     }
 }
@@ -1,10 +1,34 @@
+pub use config::{
+    AddressFilterFn, SocketFactoryFn, UcpAckedFn, UcpCloseFn, UcpConfig, UcpSample, UcpSampleFn,
+    UCP_FEATURE_BATCH, UCP_FEATURE_MTU_PROBE,
+};
+pub use congestion::{
+    CongestionControl, CongestionControlFn, NoCongestionControl, RenoCongestionControl,
+};
+pub use crypto::{ChaChaPolyCrypto, UcpCrypto, UcpCryptoRef, UcpKey};
+pub use error::UcpError;
+pub use framed::FramedUcp;
 pub use listener::{UcpListener, UcpListenerMetrics};
-pub use stream::{UcpStream, UcpStreamMetrics};
+#[cfg(feature = "pcap")]
+pub use pcap::PcapWriter;
+pub use stream::{UcpSendLimit, UcpStream, UcpStreamMetrics};
+pub use transport::{LoopbackTransport, UcpTransport, UcpTransportRef};
 
+mod config;
+mod congestion;
+mod crypto;
+mod error;
+mod framed;
+#[cfg(test)]
+mod harness;
 mod internal;
+mod limiter;
 mod listener;
 mod packet;
+#[cfg(feature = "pcap")]
+mod pcap;
 mod stream;
+mod transport;
 
 const CMD_SYN: u8 = 128;
 const CMD_SYN_ACK: u8 = 129;
@@ -12,9 +36,30 @@ const CMD_ACK: u8 = 130;
 const CMD_DATA: u8 = 131;
 const CMD_HEARTBEAT: u8 = 132;
 const CMD_HEARTBEAT_ACK: u8 = 133;
+const CMD_RST: u8 = 134;
+const CMD_BATCH: u8 = 135;
+const CMD_FIN: u8 = 136;
+const CMD_FIN_ACK: u8 = 137;
+const CMD_MTU_PROBE: u8 = 138;
+const CMD_MTU_PROBE_ACK: u8 = 139;
+const CMD_REKEY: u8 = 140;
 const UCP_PACKET_META_SIZE: usize = 29;
+const UCP_PACKET_SIZE: usize = 1400;
+// Fits the 576 bytes every IPv4 path carries after the IP and UDP headers
+const MIN_PACKET_SIZE: usize = 548;
 const DEFAULT_WINDOW: u32 = 512;
 const DEFAULT_RTO: u32 = 100;
+const MIN_RTO: u32 = 10;
+const MAX_RTO: u32 = 60000;
 const HEARTBEAT_INTERVAL_MILLIS: u128 = 2500;
 const UCP_STREAM_BROKEN_MILLIS: u128 = 20000;
+const UCP_STREAM_STALLED_MILLIS: u128 = 20000;
+const UCP_REKEY_PACKETS: u64 = 1 << 24;
 const SKIP_RESEND_TIMES: u32 = 2;
+const RTO_BACKOFF_LIMIT: u32 = 6;
+const SEND_RETRY_TIMES: usize = 2;
+const MIN_SEND_DELAY_MILLIS: u32 = 40;
+const DELIVERY_RATE_INTERVAL_MILLIS: u128 = 100;
+const MTU_PROBE_LOSSES: u32 = 3;
+// Probing stops once the search range is narrower than this
+const MTU_PROBE_STEP: usize = 16;
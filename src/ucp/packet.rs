@@ -1,15 +1,26 @@
 use crate::ucp::*;
 use crc::crc32;
+#[cfg(test)]
+use std::cell::Cell;
 use std::cmp::min;
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 
 #[derive(Clone)]
 pub(super) struct UcpPacket {
     read_pos: usize,
-    pub(super) buf: [u8; 1400],
+    // One spare byte past UCP_PACKET_SIZE to detect oversized datagrams
+    pub(super) buf: [u8; UCP_PACKET_SIZE + 1],
     pub(super) size: usize,
+    // Bytes the packet may grow to when writing payload
+    pub(super) limit: usize,
     pub(super) payload: u16,
+    // Key the last pack sealed the payload with in place
+    sealed: Option<UcpKey>,
+    // Rekeys the sender went through before sealing, sent after the seal
+    pub(super) epoch: u32,
     pub(super) skip_times: u32,
+    pub(super) first_timestamp: u32,
 
     pub(super) session_id: u32,
     pub(super) timestamp: u32,
@@ -23,11 +34,15 @@ pub(super) struct UcpPacket {
 impl UcpPacket {
     pub(super) fn new() -> UcpPacket {
         UcpPacket {
-            buf: [0; 1400],
+            buf: [0; UCP_PACKET_SIZE + 1],
             read_pos: 0,
             size: 0,
+            limit: UCP_PACKET_SIZE,
             payload: 0,
+            sealed: None,
+            epoch: 0,
             skip_times: 0,
+            first_timestamp: 0,
             session_id: 0,
             timestamp: 0,
             window: 0,
@@ -38,89 +53,154 @@ impl UcpPacket {
         }
     }
 
-    pub(super) fn parse(&mut self) -> bool {
-        if !self.is_legal() {
+    // key picks the key the peer sealed the payload with, from the header
+    pub(super) fn parse<F>(&mut self, config: &UcpConfig, key: F) -> bool
+    where
+        F: FnOnce(&UcpPacket) -> Option<UcpKey>,
+    {
+        // Keep every slice below within buf whatever size was set to
+        if self.size < UCP_PACKET_META_SIZE || self.size > self.buf.len() {
             return false;
         }
 
-        self.payload = (self.size - UCP_PACKET_META_SIZE) as u16;
-        self.read_pos = UCP_PACKET_META_SIZE;
+        if !self.is_legal(config) {
+            return false;
+        }
 
-        let mut offset = 4;
-        self.session_id = self.parse_u32(&mut offset);
-        self.timestamp = self.parse_u32(&mut offset);
-        self.window = self.parse_u32(&mut offset);
-        self.xmit = self.parse_u32(&mut offset);
-        self.una = self.parse_u32(&mut offset);
-        self.seq = self.parse_u32(&mut offset);
-        self.cmd = self.parse_u8(&mut offset);
-
-        self.cmd >= CMD_SYN && self.cmd <= CMD_HEARTBEAT_ACK
-    }
-
-    pub(super) fn pack(&mut self) {
-        let mut offset = 4;
-        let session_id = self.session_id;
-        let timestamp = self.timestamp;
-        let window = self.window;
-        let xmit = self.xmit;
-        let una = self.una;
-        let seq = self.seq;
-        let cmd = self.cmd;
-
-        self.write_u32(&mut offset, session_id);
-        self.write_u32(&mut offset, timestamp);
-        self.write_u32(&mut offset, window);
-        self.write_u32(&mut offset, xmit);
-        self.write_u32(&mut offset, una);
-        self.write_u32(&mut offset, seq);
-        self.write_u8(&mut offset, cmd);
-
-        offset = 0;
-        self.size = self.payload as usize + UCP_PACKET_META_SIZE;
+        self.payload = match u16::try_from(self.size - UCP_PACKET_META_SIZE) {
+            Ok(payload) => payload,
+            Err(_) => return false,
+        };
+        self.read_pos = UCP_PACKET_META_SIZE;
 
-        let digest = crc32::checksum_ieee(&self.buf[4..self.size]);
-        self.write_u32(&mut offset, digest);
+        self.parse_header().is_some()
+            && self.cmd >= CMD_SYN
+            && self.cmd <= CMD_REKEY
+            && self.decrypt(config, key)
+            && self.is_payload_legal()
     }
 
-    pub(super) fn packed_buffer(&self) -> &[u8] {
-        &self.buf[..self.size]
+    fn decrypt<F>(&mut self, config: &UcpConfig, key: F) -> bool
+    where
+        F: FnOnce(&UcpPacket) -> Option<UcpKey>,
+    {
+        let crypto = match config.crypto {
+            Some(ref crypto) => crypto,
+            None => return true,
+        };
+
+        let len = match (self.payload as usize).checked_sub(EPOCH_SIZE) {
+            Some(len) => len,
+            None => return false,
+        };
+        self.epoch =
+            match PacketReader::new(&self.buf[..self.size], self.size - EPOCH_SIZE).read_u32() {
+                Some(epoch) => epoch,
+                None => return false,
+            };
+
+        let key = match key(self) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        let (header, payload) = self.buf[4..self.size].split_at_mut(UCP_PACKET_META_SIZE - 4);
+        match crypto.decrypt(&key, header, payload, len) {
+            Some(len) => {
+                self.payload = len as u16;
+                self.size = len + UCP_PACKET_META_SIZE;
+                true
+            }
+            None => false,
+        }
     }
 
-    pub(super) fn parse_u32(&self, offset: &mut isize) -> u32 {
-        let u = unsafe { *(self.buf.as_ptr().offset(*offset) as *const u32) };
-
-        *offset += 4;
-        u32::from_be(u)
+    // Reject payloads the command can't carry before any handler reads them,
+    // SYN and DATA payloads may have any length
+    fn is_payload_legal(&self) -> bool {
+        let payload = self.payload;
+
+        match self.cmd {
+            CMD_ACK => payload.is_multiple_of(8),
+            CMD_SYN_ACK => payload == 8 || payload == 12,
+            CMD_HEARTBEAT_ACK | CMD_RST => payload == 0 || payload == 4,
+            CMD_MTU_PROBE => payload >= 4,
+            CMD_MTU_PROBE_ACK => payload == 4,
+            CMD_HEARTBEAT | CMD_FIN | CMD_FIN_ACK | CMD_REKEY => payload == 0,
+            CMD_BATCH => payload > 0,
+            _ => true,
+        }
     }
 
-    pub(super) fn parse_u8(&self, offset: &mut isize) -> u8 {
-        let u = self.buf[*offset as usize];
-        *offset += 1;
-        u
+    fn parse_header(&mut self) -> Option<()> {
+        let mut reader = PacketReader::new(&self.buf[..self.size], 4);
+        self.session_id = reader.read_u32()?;
+        self.timestamp = reader.read_u32()?;
+        self.window = reader.read_u32()?;
+        self.xmit = reader.read_u32()?;
+        self.una = reader.read_u32()?;
+        self.seq = reader.read_u32()?;
+        self.cmd = reader.read_u8()?;
+        Some(())
     }
 
-    pub(super) fn write_u32(&mut self, offset: &mut isize, u: u32) {
-        unsafe {
-            *(self.buf.as_ptr().offset(*offset) as *mut u32) = u.to_be();
+    // Seals the payload with key when config has crypto
+    pub(super) fn pack(&mut self, config: &UcpConfig, key: Option<&UcpKey>) {
+        // Resends pack again, open the previous seal under the header it
+        // was made with so the payload isn't sealed twice
+        if let Some(sealed) = self.sealed.take() {
+            if let Some(ref crypto) = config.crypto {
+                let (header, payload) =
+                    self.buf[4..UCP_PACKET_SIZE].split_at_mut(UCP_PACKET_META_SIZE - 4);
+                let len = self.size - UCP_PACKET_META_SIZE - EPOCH_SIZE;
+                crypto.decrypt(&sealed, header, payload, len);
+            }
+        }
+
+        let mut writer = PacketWriter::new(&mut self.buf, 4);
+        writer.write_u32(self.session_id);
+        writer.write_u32(self.timestamp);
+        writer.write_u32(self.window);
+        writer.write_u32(self.xmit);
+        writer.write_u32(self.una);
+        writer.write_u32(self.seq);
+        writer.write_u8(self.cmd);
+
+        self.size = self.payload as usize + UCP_PACKET_META_SIZE;
+
+        if let (Some(crypto), Some(key)) = (config.crypto.as_ref(), key) {
+            let (header, payload) =
+                self.buf[4..UCP_PACKET_SIZE].split_at_mut(UCP_PACKET_META_SIZE - 4);
+            let len = crypto.encrypt(key, header, payload, self.payload as usize);
+            self.size = len + UCP_PACKET_META_SIZE;
+            PacketWriter::new(&mut self.buf[..UCP_PACKET_SIZE], self.size).write_u32(self.epoch);
+            self.size += EPOCH_SIZE;
+            self.sealed = Some(*key);
         }
 
-        *offset += 4;
+        let digest = if config.skip_checksum {
+            0
+        } else {
+            checksum(&self.buf[4..self.size])
+        };
+        PacketWriter::new(&mut self.buf, 0).write_u32(digest);
     }
 
-    pub(super) fn write_u8(&mut self, offset: &mut isize, u: u8) {
-        self.buf[*offset as usize] = u;
-        *offset += 1;
+    pub(super) fn packed_buffer(&self) -> &[u8] {
+        &self.buf[..self.size]
     }
 
-    pub(super) fn is_legal(&self) -> bool {
-        self.size >= UCP_PACKET_META_SIZE && self.is_crc32_correct()
+    pub(super) fn is_legal(&self, config: &UcpConfig) -> bool {
+        self.size >= UCP_PACKET_META_SIZE && (config.skip_checksum || self.is_crc32_correct())
     }
 
     pub(super) fn is_crc32_correct(&self) -> bool {
-        let mut offset = 0;
-        let digest = self.parse_u32(&mut offset);
-        crc32::checksum_ieee(&self.buf[4..self.size]) == digest
+        let digest = PacketReader::new(&self.buf[..self.size], 0).read_u32();
+        digest == Some(checksum(&self.buf[4..self.size]))
+    }
+
+    pub(super) fn is_truncated(&self) -> bool {
+        self.size > UCP_PACKET_SIZE
     }
 
     pub(super) fn is_syn(&self) -> bool {
@@ -128,17 +208,21 @@ impl UcpPacket {
     }
 
     pub(super) fn remaining_load(&self) -> usize {
-        self.buf.len() - self.payload as usize - UCP_PACKET_META_SIZE
+        min(
+            self.limit.saturating_sub(self.payload_end()),
+            (u16::MAX - self.payload) as usize,
+        )
     }
 
-    pub(super) fn payload_offset(&self) -> isize {
-        (self.payload as usize + UCP_PACKET_META_SIZE) as isize
+    fn payload_end(&self) -> usize {
+        self.payload as usize + UCP_PACKET_META_SIZE
     }
 
     pub(super) fn payload_write_u32(&mut self, u: u32) -> bool {
-        if self.remaining_load() >= 4 {
-            let mut offset = self.payload_offset();
-            self.write_u32(&mut offset, u);
+        let offset = self.payload_end();
+        if self.remaining_load() >= 4
+            && PacketWriter::new(&mut self.buf[..UCP_PACKET_SIZE], offset).write_u32(u)
+        {
             self.payload += 4;
             true
         } else {
@@ -147,10 +231,10 @@ impl UcpPacket {
     }
 
     pub(super) fn payload_write_slice(&mut self, buf: &[u8]) -> bool {
-        if self.remaining_load() >= buf.len() {
-            let offset = self.payload_offset() as usize;
-            let end = offset + buf.len();
-            self.buf[offset..end].copy_from_slice(buf);
+        let offset = self.payload_end();
+        if self.remaining_load() >= buf.len()
+            && PacketWriter::new(&mut self.buf[..UCP_PACKET_SIZE], offset).write_slice(buf)
+        {
             self.payload += buf.len() as u16;
             true
         } else {
@@ -163,27 +247,284 @@ impl UcpPacket {
     }
 
     pub(super) fn payload_read_u32(&mut self) -> u32 {
-        if self.read_pos + 4 > self.size {
-            panic!("Out of range when read u32 from {}", self.read_pos);
+        let mut reader = PacketReader::new(&self.buf[..self.size], self.read_pos);
+        match reader.read_u32() {
+            Some(u) => {
+                self.read_pos = reader.position();
+                u
+            }
+            None => panic!("Out of range when read u32 from {}", self.read_pos),
         }
-
-        let mut offset = self.read_pos as isize;
-        let u = self.parse_u32(&mut offset);
-        self.read_pos = offset as usize;
-        u
     }
 
     pub(super) fn payload_read_slice(&mut self, buf: &mut [u8]) -> usize {
-        let size = min(self.payload_remaining(), buf.len());
-        let end_pos = self.read_pos + size;
+        let mut reader = PacketReader::new(&self.buf[..self.size], self.read_pos);
+        let size = reader.read_slice(buf);
+        self.read_pos = reader.position();
+        size
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static CHECKSUMS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    #[cfg(test)]
+    CHECKSUMS.with(|checksums| checksums.set(checksums.get() + 1));
+
+    crc32::checksum_ieee(buf)
+}
+
+struct PacketReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PacketReader<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> Self {
+        PacketReader { buf: buf, pos: pos }
+    }
+
+    fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        let mut b = [0u8; 1];
+        if self.read_exact(&mut b) {
+            Some(b[0])
+        } else {
+            None
+        }
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let mut b = [0u8; 4];
+        if self.read_exact(&mut b) {
+            Some(u32::from_be_bytes(b))
+        } else {
+            None
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> bool {
+        if self.remaining() < buf.len() {
+            return false;
+        }
+
+        self.read_slice(buf);
+        true
+    }
 
+    fn read_slice(&mut self, buf: &mut [u8]) -> usize {
+        let size = min(self.remaining(), buf.len());
         if size > 0 {
-            buf[0..size].copy_from_slice(&self.buf[self.read_pos..end_pos]);
-            self.read_pos = end_pos;
+            buf[..size].copy_from_slice(&self.buf[self.pos..self.pos + size]);
+            self.pos += size;
         }
 
         size
     }
 }
 
+struct PacketWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> PacketWriter<'a> {
+    fn new(buf: &'a mut [u8], pos: usize) -> Self {
+        PacketWriter { buf: buf, pos: pos }
+    }
+
+    fn write_u8(&mut self, u: u8) -> bool {
+        self.write_slice(&[u])
+    }
+
+    fn write_u32(&mut self, u: u32) -> bool {
+        self.write_slice(&u.to_be_bytes())
+    }
+
+    fn write_slice(&mut self, buf: &[u8]) -> bool {
+        if self.buf.len().saturating_sub(self.pos) < buf.len() {
+            return false;
+        }
+
+        self.buf[self.pos..self.pos + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len();
+        true
+    }
+}
+
 pub(super) type UcpPacketQueue = VecDeque<Box<UcpPacket>>;
+
+const EPOCH_SIZE: usize = 4;
+
+// Bytes sealing adds to a payload, the crypto's own and the epoch
+pub(super) fn seal_overhead(config: &UcpConfig) -> usize {
+    config
+        .crypto
+        .as_ref()
+        .map_or(0, |crypto| crypto.overhead() + EPOCH_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn checksums() -> usize {
+        CHECKSUMS.with(|checksums| checksums.get())
+    }
+
+    fn data_packet(payload: &[u8]) -> UcpPacket {
+        let mut packet = UcpPacket::new();
+        packet.session_id = 1;
+        packet.seq = 2;
+        packet.cmd = CMD_DATA;
+        packet.payload_write_slice(payload);
+        packet
+    }
+
+    // What the receiving end gets from the datagram
+    fn unpacked(packet: &UcpPacket) -> UcpPacket {
+        let mut received = UcpPacket::new();
+        received.buf[..packet.size].copy_from_slice(packet.packed_buffer());
+        received.size = packet.size;
+        received
+    }
+
+    fn payload(packet: &mut UcpPacket) -> Vec<u8> {
+        let mut payload = vec![0u8; packet.payload_remaining()];
+        packet.payload_read_slice(&mut payload);
+        payload
+    }
+
+    #[test]
+    fn skip_checksum() {
+        let config = UcpConfig {
+            skip_checksum: true,
+            ..Default::default()
+        };
+
+        let before = checksums();
+        let mut packet = data_packet(b"payload");
+        packet.pack(&config, None);
+        assert_eq!(&packet.buf[..4], &[0u8; 4]);
+
+        let mut received = unpacked(&packet);
+        assert!(received.parse(&config, |_| None));
+        assert_eq!(payload(&mut received), b"payload");
+        assert_eq!(checksums(), before);
+
+        // Without the skip both ends compute it, and a zero digest fails
+        let config = UcpConfig::default();
+        assert!(!received.is_legal(&config));
+
+        packet.pack(&config, None);
+        assert!(unpacked(&packet).parse(&config, |_| None));
+        assert_eq!(checksums(), before + 3);
+    }
+
+    #[test]
+    fn sealed_payload() {
+        let crypto = ChaChaPolyCrypto::new(b"secret");
+        let key = crypto.session_key(1);
+        let config = UcpConfig {
+            crypto: Some(Arc::new(crypto)),
+            ..Default::default()
+        };
+
+        let mut packet = data_packet(b"payload");
+        packet.pack(&config, Some(&key));
+        assert_eq!(
+            packet.size,
+            UCP_PACKET_META_SIZE + 7 + seal_overhead(&config)
+        );
+
+        let mut received = unpacked(&packet);
+        assert!(received.parse(&config, |p| {
+            assert_eq!(p.session_id, 1);
+            Some(key)
+        }));
+        assert_eq!(received.seq, 2);
+        assert_eq!(payload(&mut received), b"payload");
+
+        // A resend is opened and sealed again rather than sealed twice
+        packet.xmit += 1;
+        packet.pack(&config, Some(&key));
+        let mut received = unpacked(&packet);
+        assert!(received.parse(&config, |_| Some(key)));
+        assert_eq!(received.xmit, 1);
+        assert_eq!(payload(&mut received), b"payload");
+
+        // No key, another key or a changed header don't open it
+        assert!(!unpacked(&packet).parse(&config, |_| None));
+
+        let other = ChaChaPolyCrypto::new(b"secret").session_key(2);
+        assert!(!unpacked(&packet).parse(&config, |_| Some(other)));
+
+        let mut tampered = unpacked(&packet);
+        tampered.buf[UCP_PACKET_META_SIZE - 1] = CMD_ACK;
+        let digest = checksum(&tampered.buf[4..tampered.size]);
+        PacketWriter::new(&mut tampered.buf, 0).write_u32(digest);
+        assert!(tampered.is_legal(&config));
+        assert!(!tampered.parse(&config, |_| Some(key)));
+    }
+
+    #[test]
+    fn impossible_sizes_are_rejected() {
+        let config = UcpConfig::default();
+        let mut packet = data_packet(b"payload");
+        packet.pack(&config, None);
+        let mut received = unpacked(&packet);
+
+        let sizes = [
+            0,
+            1,
+            UCP_PACKET_META_SIZE - 1,
+            received.buf.len() + 1,
+            usize::MAX,
+        ];
+        for &size in sizes.iter() {
+            received.size = size;
+            assert!(!received.parse(&config, |_| None), "size {}", size);
+        }
+
+        received.size = packet.size;
+        assert!(received.parse(&config, |_| None));
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        // Past the checksum, every size of random bytes reaches the parser
+        let config = UcpConfig {
+            skip_checksum: true,
+            ..Default::default()
+        };
+        let sealed = UcpConfig {
+            crypto: Some(Arc::new(ChaChaPolyCrypto::new(b"secret"))),
+            ..config.clone()
+        };
+        let key = sealed.crypto.as_ref().unwrap().session_key(1);
+
+        let mut packet = UcpPacket::new();
+        for size in 0..=packet.buf.len() {
+            for _ in 0..4 {
+                for byte in packet.buf[..size].iter_mut() {
+                    *byte = rand::random();
+                }
+                packet.size = size;
+                let _ = packet.parse(&config, |_| None);
+                packet.size = size;
+                let _ = packet.parse(&sealed, |_| Some(key));
+            }
+        }
+    }
+}
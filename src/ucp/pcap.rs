@@ -0,0 +1,49 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+// Bare UCP datagrams without IP or UDP headers, for a dissector to claim
+const LINKTYPE_USER0: u32 = 147;
+
+// Writes every datagram a stream sends or receives as a pcap record
+pub struct PcapWriter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl PcapWriter {
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(PcapWriter {
+            writer: Mutex::new(Box::new(writer)),
+        })
+    }
+
+    pub(super) fn record(&self, datagram: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + datagram.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        record.extend_from_slice(datagram);
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_all(&record) {
+            error!("write pcap record failed: {}", e);
+        }
+    }
+}
@@ -2,28 +2,99 @@ use async_std::io::{self, Read, Write};
 use async_std::net::UdpSocket;
 use async_std::task;
 
+use std::any::Any;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use crate::ucp::internal::*;
+use crate::ucp::limiter::*;
 use crate::ucp::packet::*;
+use crate::ucp::{UcpConfig, UcpError, UcpTransportRef};
 
-pub use crate::ucp::internal::UcpStreamMetrics;
+pub use crate::ucp::internal::{UcpSendLimit, UcpStreamMetrics};
 
 pub struct UcpStream {
     pub(super) inner: Arc<InnerStream>,
+    user_data: Mutex<Option<Arc<dyn Any + Send + Sync>>>,
+    recv_task: Mutex<Option<task::JoinHandle<()>>>,
 }
 
 impl UcpStream {
-    pub async fn connect(server_addr: &str, metrics: Arc<UcpStreamMetrics>) -> Self {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
-        let remote_addr = SocketAddr::from_str(server_addr).unwrap();
+    pub(super) fn new(inner: Arc<InnerStream>) -> Self {
+        UcpStream {
+            inner: inner,
+            user_data: Mutex::new(None),
+            recv_task: Mutex::new(None),
+        }
+    }
+
+    pub async fn connect(server_addr: &str, metrics: Arc<UcpStreamMetrics>) -> io::Result<Self> {
+        UcpStream::connect_with_config(server_addr, metrics, UcpConfig::default()).await
+    }
+
+    pub async fn connect_with_config(
+        server_addr: &str,
+        metrics: Arc<UcpStreamMetrics>,
+        config: UcpConfig,
+    ) -> io::Result<Self> {
+        let remote_addr = SocketAddr::from_str(server_addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let local_addr = match remote_addr {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 0)),
+        };
+        let socket = config.bind(local_addr).await?;
+        Ok(UcpStream::from_socket(socket, remote_addr, metrics, config))
+    }
+
+    pub async fn connect_from(
+        local_addr: SocketAddr,
+        server_addr: &str,
+        metrics: Arc<UcpStreamMetrics>,
+        config: UcpConfig,
+    ) -> io::Result<Self> {
+        let remote_addr = SocketAddr::from_str(server_addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if local_addr.is_ipv4() != remote_addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local and remote address families differ",
+            ));
+        }
+
+        let socket = config.bind(local_addr).await?;
+        Ok(UcpStream::from_socket(socket, remote_addr, metrics, config))
+    }
 
-        let inner = Arc::new(InnerStream::new(socket, remote_addr, metrics));
+    pub fn from_socket(
+        socket: UdpSocket,
+        remote_addr: SocketAddr,
+        metrics: Arc<UcpStreamMetrics>,
+        config: UcpConfig,
+    ) -> Self {
+        UcpStream::from_transport(Arc::new(socket), remote_addr, metrics, config)
+    }
+
+    pub fn from_transport(
+        socket: UcpTransportRef,
+        remote_addr: SocketAddr,
+        metrics: Arc<UcpStreamMetrics>,
+        config: UcpConfig,
+    ) -> Self {
+        let limiter = config
+            .max_packet_rate
+            .map(|rate| Arc::new(RateLimiter::new(rate, config.packet_burst)));
+        let inner = Arc::new(InnerStream::new(
+            socket,
+            remote_addr,
+            metrics,
+            config,
+            limiter,
+        ));
         inner.connecting();
 
         let sender = inner.clone();
@@ -32,25 +103,135 @@ impl UcpStream {
         });
 
         let receiver = inner.clone();
-        task::spawn(async move {
+        let recv_task = task::spawn(async move {
             UcpStream::recv(receiver).await;
         });
 
-        UcpStream { inner: inner }
+        let stream = UcpStream::new(inner);
+        *stream.recv_task.lock().unwrap() = Some(recv_task);
+        stream
     }
 
     pub fn shutdown(&self) {
         self.inner.shutdown();
     }
 
+    pub async fn reject(&self, reason: u32) {
+        self.inner.reject(reason).await;
+    }
+
+    pub async fn abort(&self) {
+        self.inner.abort().await;
+
+        let recv_task = self.recv_task.lock().unwrap().take();
+        if let Some(recv_task) = recv_task {
+            recv_task.cancel().await;
+        }
+    }
+
+    pub async fn flush_acks(&self) {
+        self.inner.flush_acks().await;
+    }
+
+    pub fn reconfigure(&self, config: UcpConfig) -> io::Result<()> {
+        self.inner.reconfigure(config)
+    }
+
+    // Reads fail with TimedOut once blocked this long, None waits forever
+    // like TcpStream. For the std::io impls, which never block, that is once
+    // they returned WouldBlock for this long
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        self.inner.set_read_timeout(timeout);
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) {
+        self.inner.set_write_timeout(timeout);
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.inner.read_timeout()
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.inner.write_timeout()
+    }
+
+    pub fn set_rto(&self, rto: u32) {
+        self.inner.set_rto(rto);
+    }
+
+    pub fn set_cwnd(&self, cwnd: u32) {
+        self.inner.set_cwnd(cwnd);
+    }
+
+    pub fn clear_overrides(&self) {
+        self.inner.clear_overrides();
+    }
+
+    pub fn features(&self) -> u32 {
+        self.inner.features()
+    }
+
+    pub fn set_user_data<T: Any + Send + Sync>(&self, data: T) {
+        *self.user_data.lock().unwrap() = Some(Arc::new(data));
+    }
+
+    pub fn user_data<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let user_data = self.user_data.lock().unwrap().clone()?;
+        user_data.downcast::<T>().ok()
+    }
+
+    pub fn take_error(&self) -> Option<UcpError> {
+        self.inner.take_error()
+    }
+
+    pub fn debug_snapshot(&self) -> String {
+        self.inner.debug_snapshot()
+    }
+
+    pub fn flow_label(&self) -> Option<u32> {
+        self.inner.flow_label()
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.inner.session_id()
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.inner.remote_addr()
+    }
+
+    pub fn inject_data(&self, seq: u32, data: &[u8]) -> bool {
+        self.inner.inject_data(seq, data)
+    }
+
+    pub fn out_of_order_bytes(&self) -> usize {
+        self.inner.out_of_order_bytes()
+    }
+
+    pub fn metrics(&self) -> Arc<UcpStreamMetrics> {
+        self.inner.metrics()
+    }
+
+    // What holds back the data written so far, if anything
+    pub fn send_limit(&self) -> Option<UcpSendLimit> {
+        self.inner.send_limit()
+    }
+
+    // None until the delivery rate has been sampled
+    pub fn estimated_drain_time(&self) -> Option<Duration> {
+        self.inner.estimated_drain_time()
+    }
+
     pub(super) async fn send(inner: Arc<InnerStream>) {
         loop {
             task::sleep(Duration::from_millis(10)).await;
-            inner.output().await;
 
             if !inner.alive() {
                 break;
             }
+
+            inner.output().await;
         }
     }
 
@@ -69,8 +250,11 @@ impl UcpStream {
 
             if let Ok((size, remote_addr)) = result {
                 packet.size = size;
+                inner.capture(&packet.buf[..size]);
 
-                if packet.parse() {
+                if packet.is_truncated() {
+                    inner.packet_truncated(remote_addr);
+                } else if packet.parse(inner.config(), |packet| inner.opening_key(packet)) {
                     inner.input(packet, remote_addr).await;
                 } else {
                     error!("recv illgal packet from {}", remote_addr);
@@ -80,6 +264,21 @@ impl UcpStream {
     }
 }
 
+impl Drop for UcpStream {
+    fn drop(&mut self) {
+        if self.inner.release() {
+            return;
+        }
+
+        let recv_task = self.recv_task.lock().unwrap().take();
+        if let Some(recv_task) = recv_task {
+            task::spawn(async move {
+                recv_task.cancel().await;
+            });
+        }
+    }
+}
+
 impl Read for &UcpStream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -90,6 +289,25 @@ impl Read for &UcpStream {
     }
 }
 
+// Non-blocking, WouldBlock stands for Pending, the output task keeps
+// sending in the background
+impl std::io::Read for UcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.try_read(buf)
+    }
+}
+
+impl std::io::Write for UcpStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.try_write(buf)
+    }
+
+    // Succeeds once all the written data is acked by the peer
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.try_flush()
+    }
+}
+
 impl Write for &UcpStream {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -100,6 +318,7 @@ impl Write for &UcpStream {
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.inner.flush();
         Poll::Ready(Ok(()))
     }
 
@@ -107,3 +326,355 @@ impl Write for &UcpStream {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::harness::*;
+    use crate::ucp::{
+        LoopbackTransport, UcpListener, UcpListenerMetrics, UcpTransport, CMD_SYN_ACK,
+    };
+    use async_std::io::{ReadExt, WriteExt};
+    use futures::future::join;
+
+    #[test]
+    fn standalone_streams() {
+        task::block_on(async {
+            // No listener, both ends open at once and settle on one session
+            let (a_link, b_link) = LoopbackTransport::pair(client_addr(), server_addr());
+            let a_metrics = Arc::new(UcpStreamMetrics::new());
+            let a = UcpStream::from_transport(
+                a_link,
+                server_addr(),
+                a_metrics.clone(),
+                UcpConfig::default(),
+            );
+            let b = UcpStream::from_transport(
+                b_link,
+                client_addr(),
+                Arc::new(UcpStreamMetrics::new()),
+                UcpConfig::default(),
+            );
+
+            let established = wait_until(Duration::from_secs(5), || {
+                a.inner.is_established() && b.inner.is_established()
+            })
+            .await;
+            assert!(established);
+            assert_eq!(a.session_id(), b.session_id());
+
+            (&a).write_all(b"request").await.unwrap();
+            let mut buf = [0u8; 7];
+            (&b).read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"request");
+
+            let sent = pattern(4, 100_000);
+            let received = transfer(&b, &a, &sent).await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            assert!(
+                wait_until(Duration::from_secs(1), || a_metrics.get_delivered_bytes()
+                    == 7)
+                .await
+            );
+            assert!(a_metrics.get_recv_bytes() >= 100_000);
+        });
+    }
+
+    #[test]
+    fn simultaneous_open_converges() {
+        task::block_on(async {
+            for round in 0..10 {
+                // SYNs cross on the way, some of them and their answers lost
+                let (a_link, b_link) = LoopbackTransport::pair(client_addr(), server_addr());
+                for link in [&a_link, &b_link].iter() {
+                    link.set_delay(Duration::from_millis(20), Duration::from_millis(10));
+                    link.set_drop_rate(if round % 2 == 0 { 0.0 } else { 0.2 });
+                }
+
+                let a = UcpStream::from_transport(
+                    a_link.clone(),
+                    server_addr(),
+                    Arc::new(UcpStreamMetrics::new()),
+                    UcpConfig::default(),
+                );
+                let b = UcpStream::from_transport(
+                    b_link.clone(),
+                    client_addr(),
+                    Arc::new(UcpStreamMetrics::new()),
+                    UcpConfig::default(),
+                );
+                let session_id = a.session_id().min(b.session_id());
+
+                let established = wait_until(Duration::from_secs(10), || {
+                    a.inner.is_established() && b.inner.is_established()
+                })
+                .await;
+                assert!(established, "round {}", round);
+
+                // The lower session id wins on both ends
+                assert_eq!(a.session_id(), session_id);
+                assert_eq!(b.session_id(), session_id);
+
+                for link in [&a_link, &b_link].iter() {
+                    link.set_drop_rate(0.0);
+                }
+                let sent = pattern(round, 10_000);
+                let received = transfer(&a, &b, &sent).await;
+                assert_eq!(first_divergence(&sent, &received), None);
+                let received = transfer(&b, &a, &sent).await;
+                assert_eq!(first_divergence(&sent, &received), None);
+            }
+        });
+    }
+
+    #[test]
+    fn half_close_delivers_everything() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_drop_rate(0.05);
+                link.set_delay(Duration::from_millis(5), Duration::from_millis(10));
+            }
+
+            // Everything written before the FIN arrives ahead of it
+            let request = pattern(5, 300_000);
+            let (written, received) = join(
+                async {
+                    (&client).write_all(&request).await?;
+                    client.shutdown();
+                    Ok::<_, io::Error>(())
+                },
+                async {
+                    let mut received = Vec::new();
+                    (&server).read_to_end(&mut received).await?;
+                    Ok::<_, io::Error>(received)
+                },
+            )
+            .await;
+            written.unwrap();
+            assert_eq!(first_divergence(&request, &received.unwrap()), None);
+
+            let err = (&client).write_all(b"late").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+            // The peer's FIN leaves our half open until we shut it down
+            let response = pattern(6, 300_000);
+            let (written, received) = join(
+                async {
+                    (&server).write_all(&response).await?;
+                    server.shutdown();
+                    Ok::<_, io::Error>(())
+                },
+                async {
+                    let mut received = Vec::new();
+                    (&client).read_to_end(&mut received).await?;
+                    Ok::<_, io::Error>(received)
+                },
+            )
+            .await;
+            written.unwrap();
+            assert_eq!(first_divergence(&response, &received.unwrap()), None);
+
+            let closed = wait_until(Duration::from_secs(5), || {
+                !client.inner.alive() && !server.inner.alive()
+            })
+            .await;
+            assert!(closed);
+
+            // Closed by the FIN exchange rather than failed
+            let mut buf = [0u8; 1];
+            assert_eq!((&client).read(&mut buf).await.unwrap(), 0);
+            assert_eq!((&server).read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn slow_reader_is_reset() {
+        task::block_on(async {
+            let config = UcpConfig {
+                slow_reader_timeout: Some(200),
+                ..Default::default()
+            };
+            let harness = TestHarness::new(config.clone());
+            let (client, server) = harness.establish(config).await;
+
+            // Keeping up for longer than the timeout is fine
+            let mut buf = [0u8; 4];
+            for _ in 0..10 {
+                (&client).write_all(b"ping").await.unwrap();
+                (&server).read_exact(&mut buf).await.unwrap();
+                task::sleep(Duration::from_millis(50)).await;
+            }
+            assert!(server.inner.alive());
+
+            (&client).write_all(b"left unread").await.unwrap();
+            let reset = wait_until(Duration::from_secs(2), || {
+                !client.inner.alive() && !server.inner.alive()
+            })
+            .await;
+            assert!(reset);
+
+            // The peer is told with an RST instead of timing out
+            let err = (&server).read(&mut buf).await.unwrap_err();
+            assert_eq!(err.to_string(), UcpError::SlowReader.to_string());
+            let err = (&client).read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+
+    // A SYN_ACK to syn echoing seq, as a server would send it
+    fn syn_ack(syn: &UcpPacket, seq: u32) -> Vec<u8> {
+        let mut syn_ack = UcpPacket::new();
+        syn_ack.session_id = syn.session_id;
+        syn_ack.cmd = CMD_SYN_ACK;
+        syn_ack.seq = 1000;
+        syn_ack.una = syn.seq.wrapping_add(1);
+        syn_ack.window = syn.window;
+        syn_ack.payload_write_u32(seq);
+        syn_ack.payload_write_u32(syn.timestamp);
+        syn_ack.pack(&UcpConfig::default(), None);
+        syn_ack.packed_buffer().to_vec()
+    }
+
+    #[test]
+    fn syn_ack_must_echo_the_syn() {
+        task::block_on(async {
+            let (client_link, server_link) = LoopbackTransport::pair(client_addr(), server_addr());
+            let client = UcpStream::from_transport(
+                client_link,
+                server_addr(),
+                Arc::new(UcpStreamMetrics::new()),
+                UcpConfig::default(),
+            );
+
+            let mut syn = UcpPacket::new();
+            let (size, _) = server_link.recv_from(&mut syn.buf).await.unwrap();
+            syn.size = size;
+            assert!(syn.parse(&UcpConfig::default(), |_| None));
+            assert!(syn.is_syn());
+
+            // Forged answers, with the session id but not the SYN's seq
+            for seq in [syn.seq.wrapping_add(1), syn.seq.wrapping_sub(1), 0].iter() {
+                let forged = syn_ack(&syn, *seq);
+                server_link.send_to(&forged, client_addr()).await.unwrap();
+            }
+            task::sleep(Duration::from_millis(100)).await;
+            assert!(!client.inner.is_established());
+            assert!(client.inner.alive());
+
+            let answer = syn_ack(&syn, syn.seq);
+            server_link.send_to(&answer, client_addr()).await.unwrap();
+            let established =
+                wait_until(Duration::from_secs(1), || client.inner.is_established()).await;
+            assert!(established);
+        });
+    }
+
+    #[test]
+    fn read_timeout_against_a_silent_peer() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            server.set_read_timeout(Some(Duration::from_millis(200)));
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_delay(Duration::from_millis(20), Duration::from_millis(0));
+            }
+
+            // The writer keeps waking the join, which polls the read again,
+            // for longer than the timeout
+            let start = std::time::Instant::now();
+            let sent = pattern(9, 4 << 20);
+            let mut buf = [0u8; 16];
+            let (read, received) = join(
+                async {
+                    let read = (&server).read(&mut buf).await;
+                    (read, start.elapsed())
+                },
+                transfer(&server, &client, &sent),
+            )
+            .await;
+            assert_eq!(first_divergence(&sent, &received), None);
+
+            let (read, elapsed) = read;
+            assert_eq!(read.unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+            // The non-blocking read would block until the timeout passed
+            let mut client = client;
+            client.set_read_timeout(Some(Duration::from_millis(100)));
+            let err = std::io::Read::read(&mut client, &mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+            task::sleep(Duration::from_millis(150)).await;
+            let err = std::io::Read::read(&mut client, &mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        });
+    }
+
+    #[test]
+    fn dropped_stream_resets_the_peer() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            assert!(Arc::strong_count(&harness.client_link) > 1);
+
+            drop(client);
+
+            let released = wait_until(Duration::from_secs(1), || {
+                Arc::strong_count(&harness.client_link) == 1
+            })
+            .await;
+            assert!(released);
+
+            // Told at once rather than left to time out
+            let reset = wait_until(Duration::from_secs(1), || !server.inner.alive()).await;
+            assert!(reset);
+            let mut buf = [0u8; 1];
+            let err = (&server).read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+
+    fn free_port() -> SocketAddr {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn dropped_stream_releases_its_port() {
+        task::block_on(async {
+            let server_addr = free_port().to_string();
+            let mut listener = UcpListener::bind(&server_addr, Arc::new(UcpListenerMetrics::new()))
+                .await
+                .unwrap();
+            let accepted = task::spawn(async move {
+                let server = listener.incoming().await;
+                (listener, server)
+            });
+
+            let local_addr = free_port();
+            let client = UcpStream::connect_from(
+                local_addr,
+                &server_addr,
+                Arc::new(UcpStreamMetrics::new()),
+                UcpConfig::default(),
+            )
+            .await
+            .unwrap();
+            let (_listener, _server) = accepted.await;
+            assert!(std::net::UdpSocket::bind(local_addr).is_err());
+
+            drop(client);
+
+            let released = wait_until(Duration::from_secs(1), || {
+                std::net::UdpSocket::bind(local_addr).is_ok()
+            })
+            .await;
+            assert!(released);
+        });
+    }
+}
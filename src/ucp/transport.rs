@@ -0,0 +1,243 @@
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::io;
+use async_std::net::UdpSocket;
+use async_std::task;
+use async_trait::async_trait;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Datagram socket the streams and the listener send and receive through
+#[async_trait]
+pub trait UcpTransport: Send + Sync {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+pub type UcpTransportRef = Arc<dyn UcpTransport>;
+
+#[async_trait]
+impl UcpTransport for UdpSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct LoopbackFaults {
+    drop_rate: f64,
+    delay: Duration,
+    jitter: Duration,
+    max_datagram: Option<usize>,
+}
+
+// In-memory transport connected to a single peer, which can drop, delay
+// and, through jitter, reorder the datagrams it sends, or black hole the
+// ones larger than a path MTU
+pub struct LoopbackTransport {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    sender: Sender<(Vec<u8>, SocketAddr)>,
+    receiver: Receiver<(Vec<u8>, SocketAddr)>,
+    faults: Mutex<LoopbackFaults>,
+}
+
+impl LoopbackTransport {
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (Arc<Self>, Arc<Self>) {
+        let (a_sender, a_receiver) = unbounded();
+        let (b_sender, b_receiver) = unbounded();
+
+        let a_side = LoopbackTransport {
+            local_addr: a,
+            peer_addr: b,
+            sender: b_sender,
+            receiver: a_receiver,
+            faults: Mutex::new(LoopbackFaults::default()),
+        };
+
+        let b_side = LoopbackTransport {
+            local_addr: b,
+            peer_addr: a,
+            sender: a_sender,
+            receiver: b_receiver,
+            faults: Mutex::new(LoopbackFaults::default()),
+        };
+
+        (Arc::new(a_side), Arc::new(b_side))
+    }
+
+    // Fraction of sent datagrams silently lost
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.faults.lock().unwrap().drop_rate = drop_rate;
+    }
+
+    // Every datagram arrives after delay plus a random part of jitter
+    pub fn set_delay(&self, delay: Duration, jitter: Duration) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.delay = delay;
+        faults.jitter = jitter;
+    }
+
+    // Datagrams larger than max_datagram are silently lost
+    pub fn set_max_datagram(&self, max_datagram: Option<usize>) {
+        self.faults.lock().unwrap().max_datagram = max_datagram;
+    }
+}
+
+#[async_trait]
+impl UcpTransport for LoopbackTransport {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr != self.peer_addr {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "loopback transport has a single peer",
+            ));
+        }
+
+        let faults = *self.faults.lock().unwrap();
+        if let Some(max_datagram) = faults.max_datagram {
+            if buf.len() > max_datagram {
+                return Ok(buf.len());
+            }
+        }
+
+        if faults.drop_rate > 0.0 && rand::random::<f64>() < faults.drop_rate {
+            return Ok(buf.len());
+        }
+
+        let mut delay = faults.delay;
+        let jitter = faults.jitter.as_micros() as u64;
+        if jitter > 0 {
+            delay += Duration::from_micros(rand::random::<u64>() % jitter);
+        }
+
+        let datagram = (buf.to_vec(), self.local_addr);
+        if delay == Duration::from_micros(0) {
+            let _ = self.sender.try_send(datagram);
+        } else {
+            let sender = self.sender.clone();
+            task::spawn(async move {
+                task::sleep(delay).await;
+                let _ = sender.send(datagram).await;
+            });
+        }
+
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (datagram, addr) = self
+            .receiver
+            .recv()
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "peer closed"))?;
+
+        // Truncates like a UDP socket would
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::harness::*;
+    use crate::ucp::UcpConfig;
+
+    fn link() -> (Arc<LoopbackTransport>, Arc<LoopbackTransport>) {
+        LoopbackTransport::pair(client_addr(), server_addr())
+    }
+
+    async fn recv(transport: &LoopbackTransport) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; 2048];
+        let recv = io::timeout(Duration::from_millis(100), transport.recv_from(&mut buf)).await;
+        recv.ok().map(|(size, addr)| (buf[..size].to_vec(), addr))
+    }
+
+    #[test]
+    fn delivers_to_the_peer() {
+        task::block_on(async {
+            let (a, b) = link();
+            assert_eq!(a.local_addr().unwrap(), client_addr());
+
+            a.send_to(b"hello", server_addr()).await.unwrap();
+            assert_eq!(recv(&b).await, Some((b"hello".to_vec(), client_addr())));
+
+            let other = "10.0.0.3:3000".parse().unwrap();
+            assert!(a.send_to(b"hello", other).await.is_err());
+        });
+    }
+
+    #[test]
+    fn drops_lost_and_oversized_datagrams() {
+        task::block_on(async {
+            let (a, b) = link();
+
+            a.set_max_datagram(Some(100));
+            a.send_to(&[0u8; 101], server_addr()).await.unwrap();
+            a.send_to(&[0u8; 100], server_addr()).await.unwrap();
+            assert_eq!(recv(&b).await.map(|(buf, _)| buf.len()), Some(100));
+            assert_eq!(recv(&b).await, None);
+
+            a.set_drop_rate(1.0);
+            a.send_to(b"lost", server_addr()).await.unwrap();
+            assert_eq!(recv(&b).await, None);
+        });
+    }
+
+    #[test]
+    fn jitter_reorders_datagrams() {
+        task::block_on(async {
+            let (a, b) = link();
+            a.set_delay(Duration::from_millis(1), Duration::from_millis(20));
+
+            for i in 0..100u8 {
+                a.send_to(&[i], server_addr()).await.unwrap();
+            }
+
+            let mut received = Vec::new();
+            while let Some((buf, _)) = recv(&b).await {
+                received.push(buf[0]);
+            }
+
+            assert_eq!(received.len(), 100);
+            assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+
+            received.sort_unstable();
+            assert!(received.iter().enumerate().all(|(i, &n)| i == n as usize));
+        });
+    }
+
+    #[test]
+    fn stream_over_reordering_link() {
+        task::block_on(async {
+            let harness = TestHarness::new(UcpConfig::default());
+            for link in [&harness.client_link, &harness.server_link].iter() {
+                link.set_drop_rate(0.1);
+                link.set_delay(Duration::from_millis(1), Duration::from_millis(30));
+            }
+
+            let (client, server) = harness.establish(UcpConfig::default()).await;
+            let sent = pattern(3, 512 << 10);
+            let received = transfer(&client, &server, &sent).await;
+
+            assert_eq!(first_divergence(&sent, &received), None);
+            assert!(client.metrics().get_resent_packets() > 0);
+        });
+    }
+}